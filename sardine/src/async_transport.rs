@@ -0,0 +1,329 @@
+use bytes::{Buf, BufMut, BytesMut};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use srd::{SessionInfo, Srd, SrdOutcome};
+use srd_errors::SrdError;
+use transport::MAX_FRAME_SIZE;
+use Result;
+
+// Frames of the blocking transport: a little-endian u32 size, then the message
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SrdCodec;
+
+impl Decoder for SrdCodec {
+    type Item = Vec<u8>;
+    type Error = SrdError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<u8>>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let mut size = [0u8; 4];
+        size.copy_from_slice(&src[..4]);
+        let size = u32::from_le_bytes(size) as usize;
+
+        if size == 0 || size > MAX_FRAME_SIZE {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        if src.len() < 4 + size {
+            src.reserve(4 + size - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        Ok(Some(src.split_to(size).to_vec()))
+    }
+}
+
+impl Encoder<Vec<u8>> for SrdCodec {
+    type Error = SrdError;
+
+    fn encode(&mut self, frame: Vec<u8>, dst: &mut BytesMut) -> Result<()> {
+        if frame.is_empty() || frame.len() > MAX_FRAME_SIZE {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        dst.reserve(4 + frame.len());
+        dst.put_u32_le(frame.len() as u32);
+        dst.put_slice(&frame);
+        Ok(())
+    }
+}
+
+// Runs the handshake of a client context over an async stream. The crate can't use async fn, so this is a future to
+// await like one. It resolves to the context, for the secure channel or exported keys, and what has been negotiated.
+// It has to be polled within a tokio runtime, or it fails.
+pub fn connect<S: AsyncRead + AsyncWrite + Unpin>(stream: S, srd: Srd) -> Handshake<S> {
+    let state = if srd.config().is_server() {
+        State::Failed(SrdError::BadSequence)
+    } else {
+        State::Step(Vec::new())
+    };
+
+    Handshake::new(stream, srd, state)
+}
+
+// Same as connect(), for a server context. As with server_handshake(), a context with a result message and no
+// credential validator is refused before anything is read.
+pub fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: S, srd: Srd) -> Handshake<S> {
+    let state = if srd.config().is_server() && !srd.needs_verdict() {
+        State::Receive
+    } else {
+        State::Failed(SrdError::BadSequence)
+    };
+
+    Handshake::new(stream, srd, state)
+}
+
+// Steps run on the blocking pool: the Diffie-Hellman computations would stall the other tasks of the runtime.
+pub struct Handshake<S> {
+    framed: Framed<S, SrdCodec>,
+    srd: Option<Srd>,
+    state: State,
+}
+
+enum State {
+    Receive,
+    Step(Vec<u8>),
+    Stepping(JoinHandle<(Srd, Result<SrdOutcome>)>),
+    Send(Vec<u8>, After),
+    Flush(After),
+    Failed(SrdError),
+    Done,
+}
+
+// What comes once a message is sent
+enum After {
+    Receive,
+    Complete(SessionInfo),
+    Fail(SrdError),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake<S> {
+    fn new(stream: S, srd: Srd, state: State) -> Handshake<S> {
+        Handshake {
+            framed: Framed::new(stream, SrdCodec),
+            srd: Some(srd),
+            state,
+        }
+    }
+
+    fn complete(&mut self, session_info: SessionInfo) -> Result<(Srd, SessionInfo)> {
+        match self.srd.take() {
+            Some(srd) => Ok((srd, session_info)),
+            None => Err(SrdError::Internal("Handshake context lost".to_owned())),
+        }
+    }
+
+    // The next state, once a step is done
+    fn after_step(srd: &mut Srd, outcome: Result<SrdOutcome>) -> State {
+        match outcome {
+            Ok(SrdOutcome::Send(output)) => State::Send(output, After::Receive),
+            Ok(SrdOutcome::SendAndComplete(output, session_info)) => {
+                State::Send(output, After::Complete(session_info))
+            }
+            Ok(SrdOutcome::Complete(session_info)) => State::Flush(After::Complete(session_info)),
+            // Refused by accept()
+            Ok(SrdOutcome::AwaitingVerdict) => State::Failed(SrdError::BadSequence),
+            // The peer is told why, if it is still listening
+            Err(e) => match srd.get_output_data().clone() {
+                Some(error_msg) => State::Send(error_msg, After::Fail(e)),
+                None => State::Failed(e),
+            },
+        }
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<Result<(Srd, SessionInfo)>> {
+        loop {
+            self.state = match mem::replace(&mut self.state, State::Done) {
+                State::Receive => match Pin::new(&mut self.framed).poll_next(cx) {
+                    Poll::Pending => {
+                        self.state = State::Receive;
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Some(Ok(input))) => State::Step(input),
+                    Poll::Ready(Some(Err(e))) => State::Failed(e),
+                    Poll::Ready(None) => State::Failed(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                },
+                State::Step(input) => match (self.srd.take(), Handle::try_current()) {
+                    (Some(mut srd), Ok(runtime)) => State::Stepping(runtime.spawn_blocking(move || {
+                        let outcome = srd.step(&input);
+                        (srd, outcome)
+                    })),
+                    (srd, Err(_)) => {
+                        self.srd = srd;
+                        State::Failed(SrdError::Internal("Handshake polled outside of a tokio runtime".to_owned()))
+                    }
+                    (None, _) => State::Failed(SrdError::Internal("Handshake context lost".to_owned())),
+                },
+                State::Stepping(mut task) => match Pin::new(&mut task).poll(cx) {
+                    Poll::Pending => {
+                        self.state = State::Stepping(task);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Ok((mut srd, outcome))) => {
+                        let state = Self::after_step(&mut srd, outcome);
+                        self.srd = Some(srd);
+                        state
+                    }
+                    Poll::Ready(Err(_)) => State::Failed(SrdError::Internal("Handshake step failed".to_owned())),
+                },
+                State::Send(output, after) => match Pin::new(&mut self.framed).poll_ready(cx) {
+                    Poll::Pending => {
+                        self.state = State::Send(output, after);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(result) => {
+                        match result.and_then(|()| Pin::new(&mut self.framed).start_send(output)) {
+                            Ok(()) => State::Flush(after),
+                            Err(e) => State::Failed(after.error_or(e)),
+                        }
+                    }
+                },
+                State::Flush(after) => match Pin::new(&mut self.framed).poll_flush(cx) {
+                    Poll::Pending => {
+                        self.state = State::Flush(after);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Err(e)) => State::Failed(after.error_or(e)),
+                    Poll::Ready(Ok(())) => match after {
+                        After::Receive => State::Receive,
+                        After::Complete(session_info) => return Poll::Ready(self.complete(session_info)),
+                        After::Fail(e) => State::Failed(e),
+                    },
+                },
+                State::Failed(e) => return Poll::Ready(Err(e)),
+                // Polled again after the handshake ended
+                State::Done => return Poll::Ready(Err(SrdError::BadSequence)),
+            };
+        }
+    }
+}
+
+impl After {
+    // An error while telling the peer about a failure doesn't hide the failure
+    fn error_or(self, error: SrdError) -> SrdError {
+        match self {
+            After::Fail(e) => e,
+            _ => error,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = Result<(Srd, SessionInfo)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().poll_handshake(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_transport::{accept, connect, SrdCodec};
+    use blobs::LogonBlob;
+    use bytes::BytesMut;
+    use srd::SrdState;
+    use srd_config::SrdBuilder;
+    use srd_errors::SrdError;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+    use tokio::io::duplex;
+    use tokio::runtime::Builder;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn async_handshake() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let (client_stream, server_stream) = duplex(1024);
+
+        let mut client = SrdBuilder::new(false).build().unwrap();
+        let server = SrdBuilder::new(true).build().unwrap();
+        client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+        let server = runtime.spawn(accept(server_stream, server));
+        let (client, _) = runtime.block_on(connect(client_stream, client)).unwrap();
+        let (server, session_info) = runtime.block_on(server).unwrap().unwrap();
+
+        assert_eq!(client.state(), SrdState::Complete);
+        assert_eq!(server.state(), SrdState::Complete);
+        assert_eq!(session_info.blob().unwrap().blob_type(), "Logon");
+        assert_eq!(
+            server.get_blob::<LogonBlob>().unwrap().unwrap(),
+            LogonBlob::new("fdubois", "Dummy123")
+        );
+
+        // The client gets the error of the server
+        let (client_stream, server_stream) = duplex(1024);
+        let client = SrdBuilder::new(false).build().unwrap();
+        let server = SrdBuilder::new(true).build().unwrap();
+
+        let server = runtime.spawn(accept(server_stream, server));
+        let mut handshake = connect(client_stream, client);
+        match runtime.block_on(&mut handshake) {
+            Err(SrdError::MissingBlob) => {}
+            _ => assert!(false),
+        }
+        // Polling a finished handshake again is an error, not a panic
+        match runtime.block_on(&mut handshake) {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+        match runtime.block_on(server).unwrap() {
+            Err(SrdError::PeerError(code, _)) if code == SrdError::MissingBlob.code() => {}
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn refused_handshakes() {
+        // A server that would have nothing to accept the credentials with
+        let (_, server_stream) = duplex(1024);
+        let server = SrdBuilder::new(true).result_message(true).build().unwrap();
+        let runtime = Builder::new_current_thread().build().unwrap();
+        match runtime.block_on(accept(server_stream, server)) {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+
+        // Outside of a tokio runtime, the handshake fails instead of panicking
+        let (client_stream, _) = duplex(1024);
+        let mut handshake = connect(client_stream, SrdBuilder::new(false).build().unwrap());
+        let mut context = Context::from_waker(Waker::noop());
+        match Pin::new(&mut handshake).poll(&mut context) {
+            Poll::Ready(Err(SrdError::Internal(_))) => {}
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn codec() {
+        let mut buffer = BytesMut::new();
+        SrdCodec.encode(b"SRD".to_vec(), &mut buffer).unwrap();
+        assert!(SrdCodec.encode(Vec::new(), &mut buffer).is_err());
+
+        let mut partial = BytesMut::from(&buffer[..5]);
+        assert!(SrdCodec.decode(&mut partial).unwrap().is_none());
+        assert_eq!(SrdCodec.decode(&mut buffer).unwrap().unwrap(), b"SRD");
+        assert!(buffer.is_empty());
+
+        let mut oversized = BytesMut::from(&[0xffu8, 0xff, 0xff, 0xff][..]);
+        match SrdCodec.decode(&mut oversized) {
+            Err(SrdError::InvalidDataLength) => {}
+            _ => assert!(false),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Read;
+use std::io::Write;
+
+use blobs::Blob;
+use messages::Message;
+use Result;
+
+pub const SRD_CHANGE_BLOB_FLAG_LOGON: u16 = 0x0001;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ChangeBlob {
+    username: String,
+    old_password: String,
+    new_password: String,
+    flags: u16,
+}
+
+impl ChangeBlob {
+    pub fn new(username: &str, old_password: &str, new_password: &str, flags: u16) -> ChangeBlob {
+        ChangeBlob {
+            username: username.to_string(),
+            old_password: old_password.to_string(),
+            new_password: new_password.to_string(),
+            flags,
+        }
+    }
+
+    pub fn get_username(&self) -> String {
+        self.username.clone()
+    }
+
+    pub fn get_old_password(&self) -> String {
+        self.old_password.clone()
+    }
+
+    pub fn get_new_password(&self) -> String {
+        self.new_password.clone()
+    }
+
+    pub fn get_flags(&self) -> u16 {
+        self.flags
+    }
+
+    // Perform a logon before changing the password (single-use passwords)
+    pub fn has_logon(&self) -> bool {
+        self.flags & SRD_CHANGE_BLOB_FLAG_LOGON != 0
+    }
+}
+
+impl Blob for ChangeBlob {
+    fn blob_type() -> &'static str {
+        "Change"
+    }
+}
+
+impl Message for ChangeBlob {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let username_length = reader.read_u16::<LittleEndian>()?;
+        let old_password_length = reader.read_u16::<LittleEndian>()?;
+        let new_password_length = reader.read_u16::<LittleEndian>()?;
+        let flags = reader.read_u16::<LittleEndian>()?;
+
+        let mut username_buf = vec![0u8; username_length as usize];
+        reader.read_exact(&mut username_buf)?;
+        reader.read_u8()?;
+        let username: String = String::from_utf8_lossy(username_buf.as_slice()).to_string();
+
+        let mut old_password_buf = vec![0u8; old_password_length as usize];
+        reader.read_exact(&mut old_password_buf)?;
+        reader.read_u8()?;
+        let old_password: String = String::from_utf8_lossy(old_password_buf.as_slice()).to_string();
+
+        let mut new_password_buf = vec![0u8; new_password_length as usize];
+        reader.read_exact(&mut new_password_buf)?;
+        reader.read_u8()?;
+        let new_password: String = String::from_utf8_lossy(new_password_buf.as_slice()).to_string();
+
+        Ok(ChangeBlob::new(&username, &old_password, &new_password, flags))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<LittleEndian>(self.username.len() as u16)?;
+        writer.write_u16::<LittleEndian>(self.old_password.len() as u16)?;
+        writer.write_u16::<LittleEndian>(self.new_password.len() as u16)?;
+        writer.write_u16::<LittleEndian>(self.flags)?;
+        writer.write_all(self.username.as_bytes())?;
+        writer.write_u8(0u8)?;
+        writer.write_all(self.old_password.as_bytes())?;
+        writer.write_u8(0u8)?;
+        writer.write_all(self.new_password.as_bytes())?;
+        writer.write_u8(0u8)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use blobs::{ChangeBlob, SRD_CHANGE_BLOB_FLAG_LOGON};
+    use messages::Message;
+    use std;
+
+    #[test]
+    fn change_blob_encoding() {
+        let blob = ChangeBlob::new("fdubois", "Dummy123", "1234567ßẞ", SRD_CHANGE_BLOB_FLAG_LOGON);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        blob.write_to(&mut buffer).unwrap();
+
+        let mut expected = vec![7, 0, 8, 0, 12, 0, 1, 0];
+        expected.extend_from_slice(b"fdubois\0Dummy123\0");
+        expected.extend_from_slice("1234567ßẞ".as_bytes());
+        expected.push(0);
+        assert_eq!(buffer, expected);
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        let blob_read = ChangeBlob::read_from(&mut cursor).unwrap();
+        assert_eq!(blob_read, blob);
+        assert!(blob_read.has_logon());
+    }
+}
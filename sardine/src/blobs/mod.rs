@@ -9,8 +9,10 @@ use rand::rngs::OsRng;
 use rand::RngCore;
 
 mod basic_blob;
+mod change_blob;
 mod logon_blob;
 pub use self::basic_blob::BasicBlob;
+pub use self::change_blob::{ChangeBlob, SRD_CHANGE_BLOB_FLAG_LOGON};
 pub use self::logon_blob::LogonBlob;
 
 #[cfg(feature = "wasm")]
@@ -36,6 +38,16 @@ impl SrdBlob {
             data,
         }
     }
+
+    pub fn new_change(username: &str, old_password: &str, new_password: &str, flags: u16) -> SrdBlob {
+        let change = ChangeBlob::new(username, old_password, new_password, flags);
+        let mut data = Vec::new();
+        change.write_to(&mut data).unwrap();
+        SrdBlob {
+            blob_type: "Change".to_string(),
+            data,
+        }
+    }
 }
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -0,0 +1,175 @@
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use srd_errors::SrdError;
+use Result;
+
+cfg_if! {
+    if #[cfg(feature = "wasm")] {
+        use wasm_bindgen::prelude::*;
+    }
+}
+
+// Signature algorithms whose hash is used as is by tls-server-end-point (RFC 5929 section 4.1). Any other algorithm,
+// MD5 and SHA-1 included, is hashed with SHA-256.
+const SHA384_WITH_RSA: &[u8] = b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0c";
+const SHA512_WITH_RSA: &[u8] = b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0d";
+const ECDSA_WITH_SHA384: &[u8] = b"\x2a\x86\x48\xce\x3d\x04\x03\x03";
+const ECDSA_WITH_SHA512: &[u8] = b"\x2a\x86\x48\xce\x3d\x04\x03\x04";
+const RSASSA_PSS: &[u8] = b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0a";
+const SHA384: &[u8] = b"\x60\x86\x48\x01\x65\x03\x04\x02\x02";
+const SHA512: &[u8] = b"\x60\x86\x48\x01\x65\x03\x04\x02\x03";
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_OID: u8 = 0x06;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+// What the channel binding data given to a context is, and how it is turned into the CBT.
+//
+// RawCert is the original SRD behavior: the data is the certificate and is used as is. The other types are the TLS
+// channel bindings and their name is part of the CBT, so both sides have to use the same type:
+// - TlsServerEndPoint (RFC 5929): the data is the DER server certificate, hashed according to its signature algorithm
+// - TlsExporter (RFC 9266): the data is the 32 bytes exported with the label "EXPORTER-Channel-Binding"
+// - TlsUnique (RFC 5929): the data is the first Finished message of the TLS handshake
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelBinding {
+    RawCert,
+    TlsServerEndPoint,
+    TlsExporter,
+    TlsUnique,
+}
+
+impl ChannelBinding {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChannelBinding::RawCert => "raw-cert",
+            ChannelBinding::TlsServerEndPoint => "tls-server-end-point",
+            ChannelBinding::TlsExporter => "tls-exporter",
+            ChannelBinding::TlsUnique => "tls-unique",
+        }
+    }
+
+    // Data included in the CBT: "<name>:<channel binding>" (RFC 5056 section 2.1), except for RawCert
+    pub fn cbt_input(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let binding = match *self {
+            ChannelBinding::RawCert => return Ok(data.to_vec()),
+            ChannelBinding::TlsServerEndPoint => tls_server_end_point(data)?,
+            ChannelBinding::TlsExporter | ChannelBinding::TlsUnique => data.to_vec(),
+        };
+
+        let mut input = Vec::new();
+        input.extend_from_slice(self.name().as_bytes());
+        input.push(b':');
+        input.extend_from_slice(&binding);
+        Ok(input)
+    }
+}
+
+fn tls_server_end_point(cert: &[u8]) -> Result<Vec<u8>> {
+    let hash = match signature_hash(cert)? {
+        Some(ref oid) if oid.as_slice() == SHA384 => Sha384::digest(cert).to_vec(),
+        Some(ref oid) if oid.as_slice() == SHA512 => Sha512::digest(cert).to_vec(),
+        _ => Sha256::digest(cert).to_vec(),
+    };
+    Ok(hash)
+}
+
+// Only the certificate signature algorithm is needed, so this reads just enough DER to get to it:
+// Certificate ::= SEQUENCE { tbsCertificate SEQUENCE, signatureAlgorithm AlgorithmIdentifier, ... }
+fn signature_hash(cert: &[u8]) -> Result<Option<Vec<u8>>> {
+    let (certificate, _) = read_tlv(cert, DER_SEQUENCE)?;
+    let (_, rest) = read_tlv(certificate, DER_SEQUENCE)?;
+    let (algorithm, _) = read_tlv(rest, DER_SEQUENCE)?;
+    let (oid, params) = read_tlv(algorithm, DER_OID)?;
+
+    let hash = match oid {
+        SHA384_WITH_RSA | ECDSA_WITH_SHA384 => Some(SHA384.to_vec()),
+        SHA512_WITH_RSA | ECDSA_WITH_SHA512 => Some(SHA512.to_vec()),
+        // RSASSA-PSS-params ::= SEQUENCE { hashAlgorithm [0] AlgorithmIdentifier DEFAULT sha1, ... }
+        RSASSA_PSS => read_tlv(params, DER_SEQUENCE)
+            .and_then(|(pss_params, _)| read_tlv(pss_params, DER_CONTEXT_0))
+            .and_then(|(hash_algorithm, _)| read_tlv(hash_algorithm, DER_SEQUENCE))
+            .and_then(|(hash_algorithm, _)| read_tlv(hash_algorithm, DER_OID))
+            .ok()
+            .map(|(hash_oid, _)| hash_oid.to_vec()),
+        _ => None,
+    };
+    Ok(hash)
+}
+
+// Returns the value of the DER element at the start of the buffer, and what follows it
+fn read_tlv(buffer: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    if buffer.len() < 2 || buffer[0] != tag {
+        return Err(SrdError::InvalidCert);
+    }
+
+    let (len, header_len) = match buffer[1] {
+        len if len < 0x80 => (len as usize, 2),
+        len_size @ 0x81..=0x84 => {
+            let len_size = (len_size & 0x7f) as usize;
+            if buffer.len() < 2 + len_size {
+                return Err(SrdError::InvalidCert);
+            }
+            let len = buffer[2..2 + len_size]
+                .iter()
+                .fold(0usize, |len, &b| (len << 8) | b as usize);
+            (len, 2 + len_size)
+        }
+        _ => return Err(SrdError::InvalidCert),
+    };
+
+    if buffer.len() - header_len < len {
+        return Err(SrdError::InvalidCert);
+    }
+
+    Ok((&buffer[header_len..header_len + len], &buffer[header_len + len..]))
+}
+
+#[cfg(test)]
+mod test {
+    use channel_binding::ChannelBinding;
+    use sha2::{Digest, Sha256, Sha384};
+
+    // Certificate { SEQUENCE {}, AlgorithmIdentifier { <oid>, NULL }, BIT STRING {} }
+    fn fake_cert(oid: &[u8]) -> Vec<u8> {
+        let mut algorithm = vec![0x06, oid.len() as u8];
+        algorithm.extend_from_slice(oid);
+        algorithm.extend_from_slice(&[0x05, 0x00]);
+
+        let mut content = vec![0x30, 0x00, 0x30, algorithm.len() as u8];
+        content.extend_from_slice(&algorithm);
+        content.extend_from_slice(&[0x03, 0x01, 0x00]);
+
+        let mut cert = vec![0x30, 0x81, content.len() as u8];
+        cert.extend_from_slice(&content);
+        cert
+    }
+
+    #[test]
+    fn tls_server_end_point_hash() {
+        let sha256_with_rsa = fake_cert(b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x0b");
+        let sha1_with_rsa = fake_cert(b"\x2a\x86\x48\x86\xf7\x0d\x01\x01\x05");
+        let ecdsa_with_sha384 = fake_cert(b"\x2a\x86\x48\xce\x3d\x04\x03\x03");
+
+        for (cert, hash) in vec![
+            (&sha256_with_rsa, Sha256::digest(&sha256_with_rsa).to_vec()),
+            (&sha1_with_rsa, Sha256::digest(&sha1_with_rsa).to_vec()),
+            (&ecdsa_with_sha384, Sha384::digest(&ecdsa_with_sha384).to_vec()),
+        ] {
+            let mut expected = b"tls-server-end-point:".to_vec();
+            expected.extend_from_slice(&hash);
+            assert_eq!(ChannelBinding::TlsServerEndPoint.cbt_input(cert).unwrap(), expected);
+        }
+
+        assert!(ChannelBinding::TlsServerEndPoint.cbt_input(b"not a certificate").is_err());
+        assert!(ChannelBinding::TlsServerEndPoint.cbt_input(&sha256_with_rsa[..20]).is_err());
+    }
+
+    #[test]
+    fn cbt_input() {
+        assert_eq!(ChannelBinding::RawCert.cbt_input(b"data").unwrap(), b"data".to_vec());
+        assert_eq!(ChannelBinding::TlsExporter.cbt_input(b"data").unwrap(), b"tls-exporter:data".to_vec());
+        assert_eq!(ChannelBinding::TlsUnique.cbt_input(b"data").unwrap(), b"tls-unique:data".to_vec());
+    }
+}
@@ -1,11 +1,14 @@
 use srd_errors::SrdError;
 
 use chacha::{ChaCha, KeyStream};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, XChaCha20Poly1305, XNonce};
 
 cfg_if! {
     if #[cfg(feature = "aes")]{
         use aes::Aes256;
         use block_modes::{BlockMode, Cbc, block_padding::NoPadding};
+        use aes_gcm::Aes256Gcm;
     }
 }
 
@@ -14,6 +17,12 @@ use Result;
 const AES256_FLAG: u32 = 0x00000001;
 const CHACHA20_FLAG: u32 = 0x00000100;
 const XCHACHA20_FLAG: u32 = 0x00000200;
+const AES256_GCM_FLAG: u32 = 0x00000002;
+const CHACHA20_POLY1305_FLAG: u32 = 0x00000400;
+const XCHACHA20_POLY1305_FLAG: u32 = 0x00000800;
+
+// Size of the authentication tag appended by the AEAD ciphers
+pub const AEAD_TAG_SIZE: usize = 16;
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 #[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
@@ -21,6 +30,9 @@ pub enum Cipher {
     AES256,
     ChaCha20,
     XChaCha20,
+    AES256GCM,
+    ChaCha20Poly1305,
+    XChaCha20Poly1305,
 }
 
 impl Cipher {
@@ -29,6 +41,9 @@ impl Cipher {
             &Cipher::AES256 => encrypt_data_aes(data, key, iv),
             &Cipher::ChaCha20 => encrypt_data_chacha(data, key, iv),
             &Cipher::XChaCha20 => encrypt_data_xchacha(data, key, iv),
+            &Cipher::AES256GCM => encrypt_data_aes_gcm(data, key, iv),
+            &Cipher::ChaCha20Poly1305 => encrypt_data_chacha_poly(data, key, iv),
+            &Cipher::XChaCha20Poly1305 => encrypt_data_xchacha_poly(data, key, iv),
         }
     }
 
@@ -37,6 +52,26 @@ impl Cipher {
             &Cipher::AES256 => decrypt_data_aes(data, key, iv),
             &Cipher::ChaCha20 => encrypt_data_chacha(data, key, iv),
             &Cipher::XChaCha20 => encrypt_data_xchacha(data, key, iv),
+            &Cipher::AES256GCM => decrypt_data_aes_gcm(data, key, iv),
+            &Cipher::ChaCha20Poly1305 => decrypt_data_chacha_poly(data, key, iv),
+            &Cipher::XChaCha20Poly1305 => decrypt_data_xchacha_poly(data, key, iv),
+        }
+    }
+
+    // Authenticated cipher of the same family, for when integrity can't come from elsewhere
+    pub fn aead(&self) -> Cipher {
+        match self {
+            &Cipher::AES256 | &Cipher::AES256GCM => Cipher::AES256GCM,
+            &Cipher::ChaCha20 | &Cipher::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305,
+            &Cipher::XChaCha20 | &Cipher::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305,
+        }
+    }
+
+    // Number of bytes added to the plaintext by encrypt_data
+    pub fn tag_size(&self) -> usize {
+        match self {
+            &Cipher::AES256 | &Cipher::ChaCha20 | &Cipher::XChaCha20 => 0,
+            &Cipher::AES256GCM | &Cipher::ChaCha20Poly1305 | &Cipher::XChaCha20Poly1305 => AEAD_TAG_SIZE,
         }
     }
 
@@ -45,6 +80,9 @@ impl Cipher {
             &Cipher::AES256 => AES256_FLAG,
             &Cipher::ChaCha20 => CHACHA20_FLAG,
             &Cipher::XChaCha20 => XCHACHA20_FLAG,
+            &Cipher::AES256GCM => AES256_GCM_FLAG,
+            &Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305_FLAG,
+            &Cipher::XChaCha20Poly1305 => XCHACHA20_POLY1305_FLAG,
         }
     }
 
@@ -59,10 +97,28 @@ impl Cipher {
         if flags & XCHACHA20_FLAG != 0 {
             ciphers.push(Cipher::XChaCha20)
         };
+        if flags & AES256_GCM_FLAG != 0 {
+            ciphers.push(Cipher::AES256GCM)
+        };
+        if flags & CHACHA20_POLY1305_FLAG != 0 {
+            ciphers.push(Cipher::ChaCha20Poly1305)
+        };
+        if flags & XCHACHA20_POLY1305_FLAG != 0 {
+            ciphers.push(Cipher::XChaCha20Poly1305)
+        };
         ciphers
     }
 
     pub fn best_cipher(ciphers: &[Cipher]) -> Result<Cipher> {
+        if ciphers.contains(&Cipher::XChaCha20Poly1305) {
+            return Ok(Cipher::XChaCha20Poly1305);
+        };
+        if ciphers.contains(&Cipher::ChaCha20Poly1305) {
+            return Ok(Cipher::ChaCha20Poly1305);
+        };
+        if ciphers.contains(&Cipher::AES256GCM) {
+            return Ok(Cipher::AES256GCM);
+        };
         if ciphers.contains(&Cipher::XChaCha20) {
             return Ok(Cipher::XChaCha20);
         };
@@ -82,7 +138,7 @@ fn encrypt_data_aes(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
         return Err(SrdError::InvalidDataLength);
     }
 
-    let cipher = Cbc::<Aes256, NoPadding>::new_var(key, &iv[0..16])?;
+    let cipher = Cbc::<Aes256, NoPadding>::new_from_slices(key, &iv[0..16])?;
     let ciphertext = cipher.encrypt_vec(data);
 
     Ok(ciphertext)
@@ -94,12 +150,24 @@ fn decrypt_data_aes(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
         return Err(SrdError::InvalidDataLength);
     }
 
-    let cipher = Cbc::<Aes256, NoPadding>::new_var(key, &iv[0..16])?;
+    let cipher = Cbc::<Aes256, NoPadding>::new_from_slices(key, &iv[0..16])?;
     let plaintext = cipher.decrypt_vec(data)?;
 
     Ok(plaintext)
 }
 
+#[cfg(feature = "aes")]
+fn encrypt_data_aes_gcm(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key)?;
+    Ok(cipher.encrypt(&Nonce::from(nonce_96(iv)), data)?)
+}
+
+#[cfg(feature = "aes")]
+fn decrypt_data_aes_gcm(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key)?;
+    Ok(cipher.decrypt(&Nonce::from(nonce_96(iv)), data)?)
+}
+
 #[cfg(not(feature = "aes"))]
 fn encrypt_data_aes(_: &[u8], _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
     unreachable!();
@@ -110,6 +178,16 @@ fn decrypt_data_aes(_: &[u8], _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
     unreachable!();
 }
 
+#[cfg(not(feature = "aes"))]
+fn encrypt_data_aes_gcm(_: &[u8], _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
+    unreachable!();
+}
+
+#[cfg(not(feature = "aes"))]
+fn decrypt_data_aes_gcm(_: &[u8], _: &[u8], _: &[u8]) -> Result<Vec<u8>> {
+    unreachable!();
+}
+
 fn encrypt_data_chacha(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
     let mut key_ref = [0u8; 32];
     key_ref.copy_from_slice(key);
@@ -137,3 +215,66 @@ fn encrypt_data_xchacha(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
     stream.xor_read(&mut buffer)?;
     Ok(buffer)
 }
+
+fn encrypt_data_chacha_poly(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new_from_slice(key)?;
+    Ok(cipher.encrypt(&Nonce::from(nonce_96(iv)), data)?)
+}
+
+fn decrypt_data_chacha_poly(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new_from_slice(key)?;
+    Ok(cipher.decrypt(&Nonce::from(nonce_96(iv)), data)?)
+}
+
+fn encrypt_data_xchacha_poly(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new_from_slice(key)?;
+    Ok(cipher.encrypt(&XNonce::from(nonce_192(iv)), data)?)
+}
+
+fn decrypt_data_xchacha_poly(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new_from_slice(key)?;
+    Ok(cipher.decrypt(&XNonce::from(nonce_192(iv)), data)?)
+}
+
+// The AEAD ciphers take their nonce from the beginning of the IV
+fn nonce_96(iv: &[u8]) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&iv[0..12]);
+    nonce
+}
+
+fn nonce_192(iv: &[u8]) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce.copy_from_slice(&iv[0..24]);
+    nonce
+}
+
+#[cfg(test)]
+mod test {
+    use cipher::{Cipher, AEAD_TAG_SIZE};
+
+    #[test]
+    fn aead_round_trip() {
+        let key = [7u8; 32];
+        let iv = [9u8; 32];
+        let data = b"sardine delegate blob";
+
+        for cipher in Cipher::from_flags(0xffffffff) {
+            if cipher.tag_size() == 0 || (cfg!(not(feature = "aes")) && cipher == Cipher::AES256GCM) {
+                continue;
+            }
+
+            let encrypted = cipher.encrypt_data(data, &key, &iv).unwrap();
+            assert_eq!(encrypted.len(), data.len() + AEAD_TAG_SIZE);
+            assert_eq!(cipher.decrypt_data(&encrypted, &key, &iv).unwrap(), data.to_vec());
+
+            let mut tampered = encrypted.clone();
+            tampered[0] ^= 1;
+            assert!(cipher.decrypt_data(&tampered, &key, &iv).is_err());
+
+            let mut other_key = key;
+            other_key[0] ^= 1;
+            assert!(cipher.decrypt_data(&encrypted, &other_key, &iv).is_err());
+        }
+    }
+}
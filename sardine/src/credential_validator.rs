@@ -0,0 +1,13 @@
+use blobs::SrdBlob;
+
+// Checks the credentials a client delegates, as soon as the server gets them. Rejected credentials fail the handshake
+// with InvalidCredentials and, when there is a result message, the client is told so.
+pub trait CredentialValidator: Send {
+    fn validate(&mut self, blob: &SrdBlob) -> bool;
+}
+
+impl<F: FnMut(&SrdBlob) -> bool + Send> CredentialValidator for F {
+    fn validate(&mut self, blob: &SrdBlob) -> bool {
+        self(blob)
+    }
+}
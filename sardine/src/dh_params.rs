@@ -1,10 +1,23 @@
 /* https://tools.ietf.org/html/rfc3526 */
 
+use num_bigint::BigUint;
+
+use srd::fill_random;
+use Result;
+
 pub struct SrdDhParams {
     pub p_data: &'static [u8],
     pub g_data: &'static [u8],
 }
 
+// Group that isn't part of SRD_DH_PARAMS but that a client explicitly accepts from a server
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Clone)]
+pub struct DhGroup {
+    pub generator: Vec<u8>,
+    pub prime: Vec<u8>,
+}
+
 pub static SRD_DH_PARAMS: [SrdDhParams; 3] = [
     SrdDhParams {
         p_data: b"\xAC\x6B\xDB\x41\x32\x4A\x9A\x9B\xF1\x66\xDE\x5E\x13\x89\x58\x2F\
@@ -128,3 +141,105 @@ pub static SRD_DH_PARAMS: [SrdDhParams; 3] = [
         g_data: b"\x00\x13",
     },
 ];
+
+// Miller-Rabin rounds with random bases: a composite passes with probability at most 2^-64, even if it was crafted
+const MILLER_RABIN_ROUNDS: usize = 32;
+
+const SMALL_PRIMES: [u32; 24] = [3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97];
+
+// p = 2q + 1 with q prime. Only 2 and q divide p - 1, so there is no small subgroup but {1} and {1, p-1}.
+pub fn is_safe_prime(p: &BigUint) -> Result<bool> {
+    is_safe_prime_with_rounds(p, MILLER_RABIN_ROUNDS)
+}
+
+fn is_safe_prime_with_rounds(p: &BigUint, rounds: usize) -> Result<bool> {
+    let one = BigUint::from(1u8);
+    if *p < BigUint::from(5u8) || !p.bit(0) {
+        return Ok(*p == BigUint::from(5u8));
+    }
+
+    let q: BigUint = p >> 1;
+    if !is_probable_prime(&q, rounds)? {
+        return Ok(false);
+    }
+
+    // Pocklington: with q prime and q > sqrt(p), 2^(p-1) = 1 mod p and gcd(2^2 - 1, p) = 1 prove that p is prime
+    Ok(p % 3u8 != BigUint::from(0u8) && BigUint::from(2u8).modpow(&(p - &one), p) == one)
+}
+
+fn is_probable_prime(n: &BigUint, rounds: usize) -> Result<bool> {
+    let one = BigUint::from(1u8);
+    let two = BigUint::from(2u8);
+
+    if *n < two {
+        return Ok(false);
+    }
+    if !n.bit(0) {
+        return Ok(*n == two);
+    }
+    for &small_prime in SMALL_PRIMES.iter() {
+        if n % small_prime == BigUint::from(0u8) {
+            return Ok(*n == BigUint::from(small_prime));
+        }
+    }
+    // No factor up to 97: the smallest composite left is 101^2
+    if *n < BigUint::from(101u32 * 101) {
+        return Ok(true);
+    }
+
+    // n - 1 = d * 2^s
+    let n_minus_one = n - &one;
+    let s = n_minus_one.trailing_zeros().unwrap_or(0);
+    let d = &n_minus_one >> s;
+
+    let mut random = vec![0u8; (n.bits() / 8 + 16) as usize];
+    'rounds: for _ in 0..rounds {
+        fill_random(&mut random)?;
+        let base = BigUint::from_bytes_be(&random) % (n - 3u8) + &two;
+
+        let mut x = base.modpow(&d, n);
+        if x == one || x == n_minus_one {
+            continue;
+        }
+
+        for _ in 1..s {
+            x = &x * &x % n;
+            if x == n_minus_one {
+                continue 'rounds;
+            }
+        }
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use dh_params::{is_safe_prime, is_safe_prime_with_rounds, SRD_DH_PARAMS};
+    use num_bigint::BigUint;
+
+    #[test]
+    fn srd_primes_are_safe() {
+        // A prime passes every round, one is enough to catch a mistake in the tables
+        for params in SRD_DH_PARAMS.iter() {
+            assert!(is_safe_prime_with_rounds(&BigUint::from_bytes_be(params.p_data), 1).unwrap());
+        }
+    }
+
+    #[test]
+    fn safe_prime() {
+        for &p in &[5u32, 7, 11, 23, 47, 59, 83, 107, 2039] {
+            assert!(is_safe_prime(&BigUint::from(p)).unwrap());
+        }
+        for &p in &[0u32, 1, 2, 3, 13, 29, 9, 15, 561, 2047, 2041] {
+            assert!(!is_safe_prime(&BigUint::from(p)).unwrap());
+        }
+
+        // 2^127 - 1 is prime, but (2^127 - 2) / 2 isn't
+        let mersenne = (BigUint::from(1u8) << 127) - BigUint::from(1u8);
+        assert!(!is_safe_prime(&mersenne).unwrap());
+        // 2048-bit SRD prime, minus 2: not prime
+        let p = BigUint::from_bytes_be(SRD_DH_PARAMS[0].p_data) - BigUint::from(2u8);
+        assert!(!is_safe_prime(&p).unwrap());
+    }
+}
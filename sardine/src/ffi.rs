@@ -5,10 +5,19 @@ extern crate libc;
 use srd::fill_random;
 use srd::Srd;
 use blobs::SrdBlob;
+use secure_channel::{SecureChannel, RECORD_HEADER_SIZE};
+use channel_binding::ChannelBinding;
+use credential_validator::CredentialValidator;
+use nonce_cache::MemoryNonceCache;
+use server_key::{ServerPublicKey, ServerSigningKey, SignatureAlgorithm};
+use srd_config::{CbtPolicy, SrdBuilder};
+use srd_errors::SrdError;
 
 use std;
 use std::ptr::copy_nonoverlapping;
 use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[no_mangle]
 pub extern "C" fn Srd_New(is_server: bool, skip_delegation: bool) -> *mut Srd {
@@ -21,6 +30,217 @@ pub extern "C" fn Srd_Free(srd_handle: *mut Srd) {
     let _srd = unsafe { Box::from_raw(srd_handle) };
 }
 
+#[no_mangle]
+pub extern "C" fn SrdBuilder_New(is_server: bool) -> *mut SrdBuilder {
+    Box::into_raw(Box::new(SrdBuilder::new(is_server)))
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_Free(builder_handle: *mut SrdBuilder) {
+    let _builder = unsafe { Box::from_raw(builder_handle) };
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetSkipDelegation(builder_handle: *mut SrdBuilder, skip_delegation: bool) {
+    let builder = unsafe { &mut *builder_handle };
+    builder.update(|builder| builder.skip_delegation(skip_delegation));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetKeySize(builder_handle: *mut SrdBuilder, key_size: u16) {
+    let builder = unsafe { &mut *builder_handle };
+    builder.update(|builder| builder.key_size(key_size));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetMinKeySize(builder_handle: *mut SrdBuilder, min_key_size: u16) {
+    let builder = unsafe { &mut *builder_handle };
+    builder.update(|builder| builder.min_key_size(min_key_size));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetX25519(builder_handle: *mut SrdBuilder, x25519: bool) {
+    let builder = unsafe { &mut *builder_handle };
+    builder.update(|builder| builder.x25519(x25519));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetVersions(builder_handle: *mut SrdBuilder, min_version: u16, max_version: u16) {
+    let builder = unsafe { &mut *builder_handle };
+    builder.update(|builder| builder.min_version(min_version).max_version(max_version));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetCipherFlags(builder_handle: *mut SrdBuilder, flags: u32) {
+    let builder = unsafe { &mut *builder_handle };
+    builder.update(|builder| builder.cipher_flags(flags));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetCertData(builder_handle: *mut SrdBuilder, data: *const u8, data_size: libc::c_int) {
+    let builder = unsafe { &mut *builder_handle };
+    let cert_data = unsafe { std::slice::from_raw_parts::<u8>(data, data_size as usize) };
+    builder.update(|builder| builder.cert_data(Vec::from(cert_data)));
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetPakeSecret(builder_handle: *mut SrdBuilder, secret: *const u8, secret_size: libc::c_int) {
+    let builder = unsafe { &mut *builder_handle };
+    let secret = unsafe { std::slice::from_raw_parts::<u8>(secret, secret_size as usize) };
+    builder.update(|builder| builder.pake_secret(Vec::from(secret)));
+}
+
+// 1: Ed25519 (32-byte seed), 2: ECDSA P-256 (32-byte scalar)
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetSigningKey(
+    builder_handle: *mut SrdBuilder,
+    algorithm: libc::c_int,
+    key: *const u8,
+    key_size: libc::c_int,
+) -> libc::c_int {
+    let builder = unsafe { &mut *builder_handle };
+    let key = unsafe { std::slice::from_raw_parts::<u8>(key, key_size as usize) };
+
+    let signing_key = SignatureAlgorithm::from_id(algorithm as u16)
+        .ok_or(SrdError::Crypto)
+        .and_then(|algorithm| ServerSigningKey::new(algorithm, key));
+
+    match signing_key {
+        Ok(signing_key) => {
+            builder.update(|builder| builder.signing_key(signing_key));
+            1
+        }
+        Err(_) => -1,
+    }
+}
+
+// 1: Ed25519 (32 bytes), 2: ECDSA P-256 (SEC1)
+#[no_mangle]
+pub extern "C" fn SrdBuilder_PinServerKey(
+    builder_handle: *mut SrdBuilder,
+    algorithm: libc::c_int,
+    key: *const u8,
+    key_size: libc::c_int,
+) -> libc::c_int {
+    let builder = unsafe { &mut *builder_handle };
+    let key = unsafe { std::slice::from_raw_parts::<u8>(key, key_size as usize) };
+
+    let public_key = SignatureAlgorithm::from_id(algorithm as u16)
+        .ok_or(SrdError::Crypto)
+        .and_then(|algorithm| ServerPublicKey::new(algorithm, key));
+
+    match public_key {
+        Ok(public_key) => {
+            builder.update(|builder| builder.pin_server_key(public_key));
+            1
+        }
+        Err(_) => -1,
+    }
+}
+
+// 0: raw certificate, 1: tls-server-end-point, 2: tls-exporter, 3: tls-unique
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetChannelBinding(
+    builder_handle: *mut SrdBuilder,
+    channel_binding: libc::c_int,
+    data: *const u8,
+    data_size: libc::c_int,
+) -> libc::c_int {
+    let builder = unsafe { &mut *builder_handle };
+    let data = unsafe { std::slice::from_raw_parts::<u8>(data, data_size as usize) };
+
+    match channel_binding_from_int(channel_binding) {
+        Some(channel_binding) => {
+            builder.update(|builder| builder.channel_binding(channel_binding, Vec::from(data)));
+            1
+        }
+        None => -1,
+    }
+}
+
+// 0: required, 1: optional, 2: disabled
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetCbtPolicy(builder_handle: *mut SrdBuilder, cbt_policy: libc::c_int) -> libc::c_int {
+    let builder = unsafe { &mut *builder_handle };
+
+    match cbt_policy_from_int(cbt_policy) {
+        Some(cbt_policy) => {
+            builder.update(|builder| builder.cbt_policy(cbt_policy));
+            1
+        }
+        None => -1,
+    }
+}
+
+// The blob name has to be null terminated, like with Srd_SetBlob
+#[no_mangle]
+pub extern "C" fn SrdBuilder_AddChallenge(
+    builder_handle: *mut SrdBuilder,
+    blob_name: *const u8,
+    blob_name_size: libc::c_int,
+) -> libc::c_int {
+    let builder = unsafe { &mut *builder_handle };
+
+    let blob_name = unsafe { std::slice::from_raw_parts::<u8>(blob_name, blob_name_size as usize) };
+    let blob_name_len = blob_name.len();
+
+    if blob_name_len > 0 && blob_name[blob_name_len - 1] == 0 {
+        if let Ok(blob_name) = std::str::from_utf8(&blob_name[..blob_name_len - 1]) {
+            builder.update(|builder| builder.challenge(blob_name));
+            return 1;
+        }
+    }
+
+    -1
+}
+
+// Server contexts built from the builder afterwards reject client nonces seen in the last window_secs seconds
+#[no_mangle]
+pub extern "C" fn SrdBuilder_SetNonceCache(
+    builder_handle: *mut SrdBuilder,
+    capacity: libc::c_int,
+    window_secs: libc::c_int,
+) -> libc::c_int {
+    let builder = unsafe { &mut *builder_handle };
+
+    if capacity <= 0 || window_secs <= 0 {
+        return -1;
+    }
+
+    match MemoryNonceCache::new(capacity as usize, Duration::from_secs(window_secs as u64)) {
+        Ok(nonce_cache) => {
+            builder.update(|builder| builder.nonce_cache(Arc::new(nonce_cache)));
+            1
+        }
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn SrdBuilder_AllowDhGroup(
+    builder_handle: *mut SrdBuilder,
+    generator: *const u8,
+    generator_size: libc::c_int,
+    prime: *const u8,
+    prime_size: libc::c_int,
+) {
+    let builder = unsafe { &mut *builder_handle };
+    let generator = unsafe { std::slice::from_raw_parts::<u8>(generator, generator_size as usize) };
+    let prime = unsafe { std::slice::from_raw_parts::<u8>(prime, prime_size as usize) };
+    builder.update(|builder| builder.allow_dh_group(generator, prime));
+}
+
+// The builder is left untouched and can be used to create other contexts. Returns null if the configuration is invalid.
+#[no_mangle]
+pub extern "C" fn SrdBuilder_Build(builder_handle: *mut SrdBuilder) -> *mut Srd {
+    let builder = unsafe { &*builder_handle };
+
+    match builder.clone().build() {
+        Ok(srd) => Box::into_raw(Box::new(srd)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn Srd_Input(srd_handle: *mut Srd, buffer: *const u8, buffer_size: libc::c_int) -> libc::c_int {
     let srd = unsafe { &mut *srd_handle };
@@ -38,6 +258,10 @@ pub extern "C" fn Srd_Input(srd_handle: *mut Srd, buffer: *const u8, buffer_size
             }
         }
         Err(_) => {
+            // An error message for the peer, if there is one
+            if !output_data.is_empty() {
+                srd.set_output_data(output_data);
+            }
             return -1;
         }
     }
@@ -64,6 +288,70 @@ pub extern "C" fn Srd_Output(srd_handle: *mut Srd, buffer: *mut u8, buffer_size:
     return 0;
 }
 
+// Returns 1 to accept the credentials, anything else to reject them
+pub type SrdValidateCallback = extern "C" fn(
+    user_data: *mut libc::c_void,
+    blob_name: *const u8,
+    blob_name_size: libc::c_int,
+    blob_data: *const u8,
+    blob_data_size: libc::c_int,
+) -> libc::c_int;
+
+struct FfiCredentialValidator {
+    callback: SrdValidateCallback,
+    user_data: *mut libc::c_void,
+}
+
+// The caller is responsible for user_data being usable from the thread running the handshake
+unsafe impl Send for FfiCredentialValidator {}
+
+impl CredentialValidator for FfiCredentialValidator {
+    fn validate(&mut self, blob: &SrdBlob) -> bool {
+        (self.callback)(
+            self.user_data,
+            blob.blob_type().as_ptr(),
+            blob.blob_type().len() as libc::c_int,
+            blob.data().as_ptr(),
+            blob.data().len() as libc::c_int,
+        ) == 1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_SetCredentialValidator(
+    srd_handle: *mut Srd,
+    callback: SrdValidateCallback,
+    user_data: *mut libc::c_void,
+) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+
+    match srd.set_credential_validator(Box::new(FfiCredentialValidator { callback, user_data })) {
+        Ok(()) => 1,
+        Err(_) => -1,
+    }
+}
+
+// The result message is then available through Srd_Output
+#[no_mangle]
+pub extern "C" fn Srd_SendResult(srd_handle: *mut Srd, accepted: bool) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+    let mut output_data = Vec::new();
+
+    match srd.send_result(accepted, &mut output_data) {
+        Ok(()) => {
+            srd.set_output_data(output_data);
+            1
+        }
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_GetState(srd_handle: *mut Srd) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+    srd.state() as libc::c_int
+}
+
 #[no_mangle]
 pub extern "C" fn Srd_SetBlob(
     srd_handle: *mut Srd,
@@ -90,6 +378,33 @@ pub extern "C" fn Srd_SetBlob(
     return status;
 }
 
+// Same as Srd_SetBlob, for a blob sent only if the server asks for it with a challenge
+#[no_mangle]
+pub extern "C" fn Srd_AddBlob(
+    srd_handle: *mut Srd,
+    blob_name: *const u8,
+    blob_name_size: libc::c_int,
+    blob_data: *const libc::c_uchar,
+    blob_data_size: libc::c_int,
+) -> libc::c_int {
+    let mut status = -1;
+    let srd = unsafe { &mut *srd_handle };
+
+    let blob_name = unsafe { std::slice::from_raw_parts::<u8>(blob_name, blob_name_size as usize) };
+    let blob_data = unsafe { std::slice::from_raw_parts::<u8>(blob_data, blob_data_size as usize) };
+    let blob_name_len = blob_name.len();
+
+    // Last char has to be a null char (0)
+    if blob_name_len > 0 && blob_name[blob_name_len - 1] == 0 {
+        if let Ok(blob_name) = std::str::from_utf8(&blob_name[..blob_name_len - 1]) {
+            srd.add_raw_blob(SrdBlob::new(blob_name, blob_data));
+            status = 1;
+        }
+    }
+
+    status
+}
+
 #[no_mangle]
 pub extern "C" fn Srd_GetBlobName(srd_handle: *mut Srd, buffer: *mut u8, buffer_size: libc::c_int) -> libc::c_int {
     let srd = unsafe { &mut *srd_handle };
@@ -147,6 +462,73 @@ pub extern "C" fn Srd_SetCertData(srd_handle: *mut Srd, data: *const u8, data_si
     }
 }
 
+#[no_mangle]
+pub extern "C" fn Srd_SetCbtPolicy(srd_handle: *mut Srd, cbt_policy: libc::c_int) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+
+    match cbt_policy_from_int(cbt_policy).map(|cbt_policy| srd.set_cbt_policy(cbt_policy)) {
+        Some(Ok(_)) => 1,
+        _ => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_SetChannelBinding(
+    srd_handle: *mut Srd,
+    channel_binding: libc::c_int,
+    data: *const u8,
+    data_size: libc::c_int,
+) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+    let data = unsafe { std::slice::from_raw_parts::<u8>(data, data_size as usize) };
+
+    match channel_binding_from_int(channel_binding).map(|binding| srd.set_channel_binding(binding, Vec::from(data))) {
+        Some(Ok(_)) => 1,
+        _ => -1,
+    }
+}
+
+fn channel_binding_from_int(channel_binding: libc::c_int) -> Option<ChannelBinding> {
+    match channel_binding {
+        0 => Some(ChannelBinding::RawCert),
+        1 => Some(ChannelBinding::TlsServerEndPoint),
+        2 => Some(ChannelBinding::TlsExporter),
+        3 => Some(ChannelBinding::TlsUnique),
+        _ => None,
+    }
+}
+
+fn cbt_policy_from_int(cbt_policy: libc::c_int) -> Option<CbtPolicy> {
+    match cbt_policy {
+        0 => Some(CbtPolicy::Required),
+        1 => Some(CbtPolicy::Optional),
+        2 => Some(CbtPolicy::Disabled),
+        _ => None,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_SetKeySize(srd_handle: *mut Srd, key_size: u16) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+
+    match srd.set_key_size(key_size) {
+        Ok(_) => 1,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_GetKeySize(srd_handle: *mut Srd) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+    srd.get_key_size() as libc::c_int
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_GetVersion(srd_handle: *mut Srd) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+    srd.version() as libc::c_int
+}
+
 #[no_mangle]
 pub extern "C" fn Srd_GetDelegationKey(srd_handle: *mut Srd, buffer: *mut u8, buffer_size: libc::c_int) -> libc::c_int {
     let srd = unsafe { &mut *srd_handle };
@@ -185,6 +567,44 @@ pub extern "C" fn Srd_GetIntegrityKey(srd_handle: *mut Srd, buffer: *mut u8, buf
     return size;
 }
 
+#[no_mangle]
+pub extern "C" fn Srd_ExportKeyingMaterial(
+    srd_handle: *mut Srd,
+    label: *const u8,
+    label_size: libc::c_int,
+    context: *const u8,
+    context_size: libc::c_int,
+    buffer: *mut u8,
+    buffer_size: libc::c_int,
+) -> libc::c_int {
+    let srd = unsafe { &mut *srd_handle };
+
+    if label.is_null() || buffer.is_null() || label_size <= 0 || context_size < 0 || buffer_size <= 0 {
+        return -1;
+    }
+
+    let label = unsafe { std::slice::from_raw_parts::<u8>(label, label_size as usize) };
+    let context = if context.is_null() {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts::<u8>(context, context_size as usize) }
+    };
+
+    let label = match std::str::from_utf8(label) {
+        Ok(label) => label,
+        Err(_) => return -1,
+    };
+
+    match srd.export_keying_material(label, context, buffer_size as usize) {
+        Ok(keying_material) => {
+            let buffer_data = unsafe { std::slice::from_raw_parts_mut::<u8>(buffer, buffer_size as usize) };
+            buffer_data.clone_from_slice(&keying_material);
+            buffer_size
+        }
+        Err(_) => -1,
+    }
+}
+
 const IV_LEN: usize = 32;
 
 #[no_mangle]
@@ -202,6 +622,8 @@ pub extern "C" fn Srd_Encrypt(
         return -1
     }
 
+    let cipher = srd.get_cipher();
+
     if (data_size % 16) != 0 {
         return -1
     }
@@ -211,7 +633,8 @@ pub extern "C" fn Srd_Encrypt(
     }
 
     let available_len = unsafe { *output_size };
-    let required_len: usize = IV_LEN + data_size;
+    let encrypted_len: usize = data_size + cipher.tag_size();
+    let required_len: usize = IV_LEN + encrypted_len;
 
     if available_len < required_len || output.is_null() {
         unsafe { *output_size = required_len };
@@ -225,10 +648,10 @@ pub extern "C" fn Srd_Encrypt(
 
     let data = unsafe { slice::from_raw_parts(data, data_size) };
     
-    if let Ok(encrypted_data) = srd.get_cipher().encrypt_data(&data, key.as_slice(), &iv) {
+    if let Ok(encrypted_data) = cipher.encrypt_data(&data, key.as_slice(), &iv) {
         unsafe {
             copy_nonoverlapping(iv.as_ptr(), output, IV_LEN);
-            copy_nonoverlapping(encrypted_data.as_ptr(), output.offset(IV_LEN as isize), data_size);
+            copy_nonoverlapping(encrypted_data.as_ptr(), output.offset(IV_LEN as isize), encrypted_len);
             *output_size = required_len;
         }
 
@@ -253,7 +676,9 @@ pub extern "C" fn Srd_Decrypt(
         return -1
     }
 
-    if data_size < IV_LEN {
+    let cipher = srd.get_cipher();
+
+    if data_size < IV_LEN + cipher.tag_size() {
         return -1
     }
 
@@ -262,7 +687,7 @@ pub extern "C" fn Srd_Decrypt(
     }
     
     let available_len = unsafe { *output_size };
-    let required_len: usize = data_size - IV_LEN;
+    let required_len: usize = data_size - IV_LEN - cipher.tag_size();
 
     if available_len < required_len || output.is_null() {
         unsafe { *output_size = required_len };
@@ -272,7 +697,7 @@ pub extern "C" fn Srd_Decrypt(
     let data = unsafe { slice::from_raw_parts(data, data_size) };
     let iv = &data[0..IV_LEN - 1];
 
-    if let Ok(decrypted_data) = srd.get_cipher().decrypt_data(&data[IV_LEN..], key.as_slice(), &iv) {
+    if let Ok(decrypted_data) = cipher.decrypt_data(&data[IV_LEN..], key.as_slice(), &iv) {
         unsafe {
             copy_nonoverlapping(decrypted_data.as_ptr(), output, required_len);
             *output_size = required_len;
@@ -282,4 +707,95 @@ pub extern "C" fn Srd_Decrypt(
     }
 
     return -1
-}
\ No newline at end of file
+}
+
+#[no_mangle]
+pub extern "C" fn Srd_GetSecureChannel(srd_handle: *mut Srd) -> *mut SecureChannel {
+    let srd = unsafe { &mut *srd_handle };
+
+    match srd.secure_channel() {
+        Ok(channel) => Box::into_raw(Box::new(channel)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn SecureChannel_Free(channel_handle: *mut SecureChannel) {
+    let _channel = unsafe { Box::from_raw(channel_handle) };
+}
+
+#[no_mangle]
+pub extern "C" fn SecureChannel_Seal(
+    channel_handle: *mut SecureChannel,
+    data: *const u8,
+    data_size: usize,
+    output: *mut u8,
+    output_size: *mut usize
+) -> i32 {
+    let channel = unsafe { &mut *channel_handle };
+
+    if output_size.is_null() {
+        return -1
+    }
+
+    let available_len = unsafe { *output_size };
+    let required_len = channel.record_size(data_size);
+
+    if available_len < required_len || output.is_null() {
+        unsafe { *output_size = required_len };
+        return 0
+    }
+
+    let data = unsafe { slice::from_raw_parts(data, data_size) };
+
+    if let Ok(record) = channel.seal(data) {
+        unsafe {
+            copy_nonoverlapping(record.as_ptr(), output, required_len);
+            *output_size = required_len;
+        }
+
+        return 1
+    }
+
+    return -1
+}
+
+#[no_mangle]
+pub extern "C" fn SecureChannel_Open(
+    channel_handle: *mut SecureChannel,
+    data: *const u8,
+    data_size: usize,
+    output: *mut u8,
+    output_size: *mut usize
+) -> i32 {
+    let channel = unsafe { &mut *channel_handle };
+
+    if data_size < channel.record_size(0) {
+        return -1
+    }
+
+    if output_size.is_null() {
+        return -1
+    }
+
+    let available_len = unsafe { *output_size };
+    let required_len = data_size - RECORD_HEADER_SIZE - channel.cipher().tag_size();
+
+    if available_len < required_len || output.is_null() {
+        unsafe { *output_size = required_len };
+        return 0
+    }
+
+    let data = unsafe { slice::from_raw_parts(data, data_size) };
+
+    if let Ok(decrypted_data) = channel.open(data) {
+        unsafe {
+            copy_nonoverlapping(decrypted_data.as_ptr(), output, required_len);
+            *output_size = required_len;
+        }
+
+        return 1
+    }
+
+    return -1
+}
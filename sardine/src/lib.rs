@@ -1,10 +1,15 @@
 extern crate byteorder;
+extern crate hkdf;
 extern crate hmac;
 extern crate num_bigint;
 extern crate rand;
 extern crate sha2;
 
 extern crate chacha;
+extern crate chacha20poly1305;
+extern crate x25519_dalek;
+extern crate ed25519_dalek;
+extern crate p256;
 
 #[macro_use]
 extern crate cfg_if;
@@ -13,21 +18,36 @@ cfg_if! {
     if #[cfg(feature = "ser")]{
         #[macro_use]
         extern crate serde;
+        #[cfg(test)]
+        extern crate bincode;
     }
 }
 
+mod channel_binding;
 mod cipher;
+mod credential_validator;
 
 pub mod blobs;
 mod dh_params;
 mod messages;
+mod nonce_cache;
 pub mod srd;
+mod secure_channel;
+mod server_key;
+mod srd_config;
 mod srd_errors;
+mod transcript;
 
 pub type Result<T> = std::result::Result<T, srd_errors::SrdError>;
 
+pub use channel_binding::ChannelBinding;
 pub use cipher::Cipher;
-pub use srd::Srd;
+pub use credential_validator::CredentialValidator;
+pub use nonce_cache::{MemoryNonceCache, NonceCache};
+pub use secure_channel::SecureChannel;
+pub use srd::{KeyExchange, SessionInfo, Srd, SrdOutcome, SrdState};
+pub use server_key::{ServerPublicKey, ServerSigningKey, SignatureAlgorithm};
+pub use srd_config::{CbtPolicy, SrdBuilder, SrdConfig};
 pub use srd_errors::SrdError;
 
 cfg_if! {
@@ -37,6 +57,19 @@ cfg_if! {
     }
     else {
         pub mod ffi;
+        pub mod transport;
+    }
+}
+
+cfg_if! {
+    if #[cfg(all(feature = "tokio", not(feature = "wasm")))] {
+        extern crate bytes;
+        extern crate futures_core;
+        extern crate futures_sink;
+        extern crate tokio;
+        extern crate tokio_util;
+
+        pub mod async_transport;
     }
 }
 
@@ -44,6 +77,7 @@ cfg_if! {
     if #[cfg(feature = "aes")] {
         extern crate aes256 as aes;
         extern crate block_modes;
+        extern crate aes_gcm;
 
     }
 }
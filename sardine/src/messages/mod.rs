@@ -1,41 +1,84 @@
 mod srd_accept;
+mod srd_challenge;
 mod srd_confirm;
 mod srd_delegate;
+mod srd_error_msg;
 mod srd_header;
 mod srd_initiate;
 mod srd_message;
 mod srd_offer;
+mod srd_result;
 
 pub const SRD_SIGNATURE: u32 = 0x00445253;
 
+// Version 1 is the original protocol, spoken by peers that don't send a version. Version 2 derives the keys with HKDF.
+pub const SRD_VERSION_1: u16 = 1;
+pub const SRD_VERSION_2: u16 = 2;
+pub const SRD_LATEST_VERSION: u16 = SRD_VERSION_2;
+
 pub mod srd_msg_id {
     pub const SRD_INITIATE_MSG_ID: u8 = 1;
     pub const SRD_OFFER_MSG_ID: u8 = 2;
     pub const SRD_ACCEPT_MSG_ID: u8 = 3;
     pub const SRD_CONFIRM_MSG_ID: u8 = 4;
     pub const SRD_DELEGATE_MSG_ID: u8 = 5;
+    pub const SRD_ERROR_MSG_ID: u8 = 6;
+    pub const SRD_RESULT_MSG_ID: u8 = 7;
+    pub const SRD_CHALLENGE_MSG_ID: u8 = 8;
+}
+
+// Carried by an error message. The values are part of the protocol and must not change.
+pub mod srd_error_code {
+    pub const SRD_ERROR_INTERNAL: u16 = 1;
+    pub const SRD_ERROR_PROTOCOL: u16 = 2;
+    pub const SRD_ERROR_BAD_SEQUENCE: u16 = 3;
+    pub const SRD_ERROR_UNSUPPORTED_VERSION: u16 = 4;
+    pub const SRD_ERROR_INVALID_KEY_SIZE: u16 = 5;
+    pub const SRD_ERROR_INVALID_DH_PARAMS: u16 = 6;
+    pub const SRD_ERROR_INVALID_PUBLIC_KEY: u16 = 7;
+    pub const SRD_ERROR_INVALID_MAC: u16 = 8;
+    pub const SRD_ERROR_INVALID_CBT: u16 = 9;
+    pub const SRD_ERROR_INVALID_CERT: u16 = 10;
+    pub const SRD_ERROR_MISSING_BLOB: u16 = 11;
+    pub const SRD_ERROR_INVALID_CREDENTIALS: u16 = 12;
+    pub const SRD_ERROR_CIPHER: u16 = 13;
+    pub const SRD_ERROR_UNTRUSTED_SERVER: u16 = 14;
+    pub const SRD_ERROR_REPLAY: u16 = 15;
 }
 
 pub mod srd_flags {
     pub const SRD_FLAG_MAC: u16 = 0x0001;
     pub const SRD_FLAG_CBT: u16 = 0x0002;
     pub const SRD_FLAG_SKIP: u16 = 0x0004;
+    pub const SRD_FLAG_X25519: u16 = 0x0008;
+    pub const SRD_FLAG_VERSION: u16 = 0x0010;
+    pub const SRD_FLAG_SIGNATURE: u16 = 0x0020;
+    pub const SRD_FLAG_PAKE: u16 = 0x0040;
+    pub const SRD_FLAG_RESULT: u16 = 0x0080;
 }
 
 pub use messages::srd_accept::SrdAccept;
+pub use messages::srd_challenge::SrdChallenge;
 pub use messages::srd_confirm::SrdConfirm;
 pub use messages::srd_delegate::SrdDelegate;
+pub use messages::srd_error_msg::SrdErrorMsg;
 pub use messages::srd_header::SrdHeader;
 pub use messages::srd_initiate::SrdInitiate;
 pub use messages::srd_message::Message;
 pub use messages::srd_message::SrdMessage;
 pub use messages::srd_offer::SrdOffer;
+pub use messages::srd_result::{SrdResult, SRD_RESULT_SUCCESS};
 
 pub use messages::srd_accept::new_srd_accept_msg;
+pub use messages::srd_challenge::new_srd_challenge_msg;
 pub use messages::srd_confirm::new_srd_confirm_msg;
 pub use messages::srd_delegate::new_srd_delegate_msg;
+pub use messages::srd_error_msg::new_srd_error_msg;
 pub use messages::srd_initiate::new_srd_initiate_msg;
 pub use messages::srd_offer::new_srd_offer_msg;
+pub use messages::srd_offer::new_srd_x25519_offer_msg;
+pub use messages::srd_offer::X25519_KEY_SIZE;
+pub use messages::srd_result::new_srd_result_msg;
 
 fn expand_start<T: Default>(buffer: &mut Vec<T>, new_size: usize) {
     if new_size > buffer.len() {
@@ -0,0 +1,91 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use messages::{srd_message::ReadMac, srd_msg_id, Message, SrdHeader, SrdMessage};
+use srd_errors::SrdError;
+use Result;
+
+const BLOB_TYPE_SIZE_LIMIT: usize = 256;
+
+// Sent by the server after a delegate message to ask for another blob, like a one-time password. The client answers
+// with another delegate message.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SrdChallenge {
+    pub blob_type: String,
+    mac: [u8; 32],
+}
+
+impl SrdChallenge {
+    pub fn mac(&self) -> &[u8] {
+        &self.mac
+    }
+
+    pub fn set_mac(&mut self, mac: &[u8]) {
+        self.mac.clone_from_slice(mac);
+    }
+}
+
+impl Message for SrdChallenge {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self>
+        where
+            Self: Sized,
+    {
+        let blob_type_size = reader.read_u16::<LittleEndian>()? as usize;
+
+        if blob_type_size == 0 || blob_type_size > BLOB_TYPE_SIZE_LIMIT {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        let mut blob_type = vec![0u8; blob_type_size];
+        reader.read_exact(&mut blob_type)?;
+
+        let mut mac = [0u8; 32];
+        reader.read_mac(&mut mac)?;
+
+        Ok(SrdChallenge {
+            blob_type: String::from_utf8(blob_type)?,
+            mac,
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<LittleEndian>(self.blob_type.len() as u16)?;
+        writer.write_all(self.blob_type.as_bytes())?;
+        writer.write_all(&self.mac)?;
+        Ok(())
+    }
+}
+
+pub fn new_srd_challenge_msg(seq_num: u8, use_cbt: bool, blob_type: &str) -> Result<SrdMessage> {
+    if blob_type.is_empty() || blob_type.len() > BLOB_TYPE_SIZE_LIMIT {
+        return Err(SrdError::InvalidDataLength);
+    }
+
+    let hdr = SrdHeader::new(srd_msg_id::SRD_CHALLENGE_MSG_ID, seq_num, use_cbt, true);
+    let challenge = SrdChallenge {
+        blob_type: blob_type.to_owned(),
+        mac: [0u8; 32],
+    };
+
+    Ok(SrdMessage::Challenge(hdr, challenge))
+}
+
+#[cfg(test)]
+mod test {
+    use messages::{new_srd_challenge_msg, srd_msg_id::SRD_CHALLENGE_MSG_ID, Message, SrdMessage};
+    use std;
+
+    #[test]
+    fn challenge_encoding() {
+        let msg = new_srd_challenge_msg(5, true, "Otp").unwrap();
+        assert_eq!(msg.msg_type(), SRD_CHALLENGE_MSG_ID);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        assert_eq!(SrdMessage::read_from(&mut cursor).unwrap(), msg);
+
+        assert!(new_srd_challenge_msg(5, true, "").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use messages::{srd_msg_id, Message, SrdHeader, SrdMessage};
+use srd_errors::SrdError;
+use Result;
+
+const REASON_SIZE_LIMIT: usize = 256;
+
+// Sent instead of the expected message when a side fails, so the peer doesn't wait for an answer that won't come.
+// It isn't authenticated: anyone on the path can send one, so it is only reported as SrdError::PeerError and ends a
+// handshake without telling anything trustworthy about why.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SrdErrorMsg {
+    code: u16,
+    reason: String,
+}
+
+impl SrdErrorMsg {
+    pub fn code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl Message for SrdErrorMsg {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self>
+        where
+            Self: Sized,
+    {
+        let code = reader.read_u16::<LittleEndian>()?;
+        let reason_size = reader.read_u16::<LittleEndian>()? as usize;
+
+        if reason_size > REASON_SIZE_LIMIT {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        let mut reason = vec![0u8; reason_size];
+        reader.read_exact(&mut reason)?;
+
+        Ok(SrdErrorMsg {
+            code,
+            reason: String::from_utf8(reason)?,
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<LittleEndian>(self.code)?;
+        writer.write_u16::<LittleEndian>(self.reason.len() as u16)?;
+        writer.write_all(self.reason.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub fn new_srd_error_msg(seq_num: u8, error: &SrdError) -> SrdMessage {
+    let hdr = SrdHeader::new(srd_msg_id::SRD_ERROR_MSG_ID, seq_num, false, false);
+
+    // Local failures are nobody else's business
+    let mut reason = match error {
+        &SrdError::Io(_) | &SrdError::Ffi(_) | &SrdError::Internal(_) => String::new(),
+        error => error.to_string(),
+    };
+
+    if reason.len() > REASON_SIZE_LIMIT {
+        let mut size = REASON_SIZE_LIMIT;
+        while !reason.is_char_boundary(size) {
+            size -= 1;
+        }
+        reason.truncate(size);
+    }
+
+    let error_msg = SrdErrorMsg {
+        code: error.code(),
+        reason,
+    };
+
+    SrdMessage::Error(hdr, error_msg)
+}
+
+#[cfg(test)]
+mod test {
+    use messages::{new_srd_error_msg, srd_msg_id::SRD_ERROR_MSG_ID, Message, SrdMessage};
+    use std;
+    use SrdError;
+
+    #[test]
+    fn error_encoding() {
+        let msg = new_srd_error_msg(2, &SrdError::InvalidMac);
+        assert_eq!(msg.msg_type(), SRD_ERROR_MSG_ID);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        let msg_read = SrdMessage::read_from(&mut cursor).unwrap();
+        assert_eq!(msg_read, msg);
+
+        match msg_read {
+            SrdMessage::Error(_, error) => {
+                assert_eq!(error.code(), SrdError::InvalidMac.code());
+                assert_eq!(error.reason(), "MAC error");
+            }
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn error_reason() {
+        let reason = "é".repeat(200);
+        match new_srd_error_msg(0, &SrdError::Proto(reason)) {
+            SrdMessage::Error(_, error) => {
+                assert!(error.reason().len() <= 256);
+                assert_eq!(error.code(), SrdError::Proto(String::new()).code());
+            }
+            _ => assert!(false),
+        }
+
+        match new_srd_error_msg(0, &SrdError::Internal("secret detail".to_owned())) {
+            SrdMessage::Error(_, error) => assert_eq!(error.reason(), ""),
+            _ => assert!(false),
+        }
+    }
+}
@@ -58,6 +58,51 @@ impl SrdHeader {
         self.flags |= SRD_FLAG_SKIP
     }
 
+    // In an initiate message, the client supports X25519. In an offer message, the server has chosen it.
+    pub fn has_x25519(&self) -> bool {
+        self.flags & SRD_FLAG_X25519 != 0
+    }
+
+    pub fn add_x25519_flag(&mut self) {
+        self.flags |= SRD_FLAG_X25519
+    }
+
+    // The initiate and offer messages carry a protocol version
+    pub fn has_version(&self) -> bool {
+        self.flags & SRD_FLAG_VERSION != 0
+    }
+
+    pub fn add_version_flag(&mut self) {
+        self.flags |= SRD_FLAG_VERSION
+    }
+
+    // In an initiate message, the client asks for a signed offer. In an offer message, the offer is signed.
+    pub fn has_signature(&self) -> bool {
+        self.flags & SRD_FLAG_SIGNATURE != 0
+    }
+
+    pub fn add_signature_flag(&mut self) {
+        self.flags |= SRD_FLAG_SIGNATURE
+    }
+
+    // The Diffie-Hellman generator is derived from a secret shared by both sides
+    pub fn has_pake(&self) -> bool {
+        self.flags & SRD_FLAG_PAKE != 0
+    }
+
+    pub fn add_pake_flag(&mut self) {
+        self.flags |= SRD_FLAG_PAKE
+    }
+
+    // In an initiate message, the client asks for a result message. In an offer message, the server will send it.
+    pub fn has_result(&self) -> bool {
+        self.flags & SRD_FLAG_RESULT != 0
+    }
+
+    pub fn add_result_flag(&mut self) {
+        self.flags |= SRD_FLAG_RESULT
+    }
+
     pub fn validate_flags(&self, mac_expected: bool) -> Result<()> {
         if !self.has_mac() && mac_expected {
             return Err(SrdError::Proto(format!(
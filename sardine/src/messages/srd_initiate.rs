@@ -1,5 +1,5 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use messages::{srd_msg_id, Message, SrdHeader, SrdMessage};
+use messages::{srd_msg_id, Message, SrdHeader, SrdMessage, SRD_VERSION_1};
 use std::io::{Read, Write};
 use Result;
 use SrdError;
@@ -8,7 +8,7 @@ use SrdError;
 pub struct SrdInitiate {
     ciphers: u32,
     key_size: u16,
-    reserved: u16,
+    version: u16,
 }
 
 impl SrdInitiate {
@@ -21,12 +21,22 @@ impl SrdInitiate {
         Ok(SrdInitiate {
             ciphers,
             key_size,
-            reserved: 0,
+            version: 0,
         })
     }
+
     pub fn key_size(&self) -> u16 {
         self.key_size
     }
+
+    // Version 1 clients leave this field to 0
+    pub fn version(&self) -> u16 {
+        self.version.max(SRD_VERSION_1)
+    }
+
+    pub fn set_version(&mut self, version: u16) {
+        self.version = version;
+    }
 }
 
 impl Message for SrdInitiate {
@@ -37,32 +47,45 @@ impl Message for SrdInitiate {
         Ok(SrdInitiate {
             ciphers: reader.read_u32::<LittleEndian>()?,
             key_size: reader.read_u16::<LittleEndian>()?,
-            reserved: reader.read_u16::<LittleEndian>()?,
+            version: reader.read_u16::<LittleEndian>()?,
         })
     }
 
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_u32::<LittleEndian>(self.ciphers)?;
         writer.write_u16::<LittleEndian>(self.key_size)?;
-        writer.write_u16::<LittleEndian>(self.reserved)?;
+        writer.write_u16::<LittleEndian>(self.version)?;
         Ok(())
     }
 }
 
-pub fn new_srd_initiate_msg(seq_num: u8, use_cbt: bool, ciphers: u32, key_size: u16) -> Result<SrdMessage> {
-    let hdr = SrdHeader::new(srd_msg_id::SRD_INITIATE_MSG_ID, seq_num, use_cbt, false);
+pub fn new_srd_initiate_msg(
+    seq_num: u8,
+    use_cbt: bool,
+    ciphers: u32,
+    key_size: u16,
+    use_x25519: bool,
+) -> Result<SrdMessage> {
+    let mut hdr = SrdHeader::new(srd_msg_id::SRD_INITIATE_MSG_ID, seq_num, use_cbt, false);
+    if use_x25519 {
+        hdr.add_x25519_flag();
+    }
+
     let initiate = SrdInitiate::new(ciphers, key_size)?;
     Ok(SrdMessage::Initiate(hdr, initiate))
 }
 
 #[cfg(test)]
 mod test {
-    use messages::{new_srd_initiate_msg, srd_msg_id::SRD_INITIATE_MSG_ID, Message, SrdMessage, SRD_SIGNATURE};
+    use messages::{
+        new_srd_initiate_msg, srd_msg_id::SRD_INITIATE_MSG_ID, Message, SrdMessage, SRD_SIGNATURE, SRD_VERSION_1,
+        SRD_VERSION_2,
+    };
     use std;
 
     #[test]
     fn initiate_encoding() {
-        let msg = new_srd_initiate_msg(0, true, 0, 1024).unwrap();
+        let msg = new_srd_initiate_msg(0, true, 0, 1024, true).unwrap();
         assert_eq!(msg.msg_type(), SRD_INITIATE_MSG_ID);
 
         let mut buffer: Vec<u8> = Vec::new();
@@ -80,4 +103,33 @@ mod test {
             Err(_) => assert!(false),
         }
     }
+
+    #[test]
+    fn initiate_version() {
+        let mut msg = new_srd_initiate_msg(0, false, 0, 256, false).unwrap();
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+
+        // Without a version, the message is the one sent by version 1 clients
+        assert_eq!(&buffer[4..], &[1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        match SrdMessage::read_from(&mut std::io::Cursor::new(buffer.as_slice())).unwrap() {
+            SrdMessage::Initiate(hdr, initiate) => {
+                assert!(!hdr.has_version());
+                assert_eq!(initiate.version(), SRD_VERSION_1);
+            }
+            _ => assert!(false),
+        }
+
+        msg.set_version(SRD_VERSION_2);
+        buffer.clear();
+        msg.write_to(&mut buffer).unwrap();
+
+        match SrdMessage::read_from(&mut std::io::Cursor::new(buffer.as_slice())).unwrap() {
+            SrdMessage::Initiate(hdr, initiate) => {
+                assert!(hdr.has_version());
+                assert_eq!(initiate.version(), SRD_VERSION_2);
+            }
+            _ => assert!(false),
+        }
+    }
 }
@@ -17,6 +17,9 @@ pub enum SrdMessage {
     Accept(SrdHeader, SrdAccept),
     Confirm(SrdHeader, SrdConfirm),
     Delegate(SrdHeader, SrdDelegate),
+    Error(SrdHeader, SrdErrorMsg),
+    Result(SrdHeader, SrdResult),
+    Challenge(SrdHeader, SrdChallenge),
 }
 
 impl SrdMessage {
@@ -28,6 +31,9 @@ impl SrdMessage {
             SrdMessage::Accept(hdr, _) => hdr.msg_type(),
             SrdMessage::Confirm(hdr, _) => hdr.msg_type(),
             SrdMessage::Delegate(hdr, _) => hdr.msg_type(),
+            SrdMessage::Error(hdr, _) => hdr.msg_type(),
+            SrdMessage::Result(hdr, _) => hdr.msg_type(),
+            SrdMessage::Challenge(hdr, _) => hdr.msg_type(),
         }
     }
 
@@ -38,6 +44,9 @@ impl SrdMessage {
             SrdMessage::Accept(hdr, _) => hdr.signature(),
             SrdMessage::Confirm(hdr, _) => hdr.signature(),
             SrdMessage::Delegate(hdr, _) => hdr.signature(),
+            SrdMessage::Error(hdr, _) => hdr.signature(),
+            SrdMessage::Result(hdr, _) => hdr.signature(),
+            SrdMessage::Challenge(hdr, _) => hdr.signature(),
         }
     }
 
@@ -48,6 +57,9 @@ impl SrdMessage {
             SrdMessage::Accept(hdr, _) => hdr.seq_num(),
             SrdMessage::Confirm(hdr, _) => hdr.seq_num(),
             SrdMessage::Delegate(hdr, _) => hdr.seq_num(),
+            SrdMessage::Error(hdr, _) => hdr.seq_num(),
+            SrdMessage::Result(hdr, _) => hdr.seq_num(),
+            SrdMessage::Challenge(hdr, _) => hdr.seq_num(),
         }
     }
 
@@ -58,6 +70,9 @@ impl SrdMessage {
             SrdMessage::Accept(hdr, _) => hdr.has_cbt(),
             SrdMessage::Confirm(hdr, _) => hdr.has_cbt(),
             SrdMessage::Delegate(hdr, _) => hdr.has_cbt(),
+            SrdMessage::Error(hdr, _) => hdr.has_cbt(),
+            SrdMessage::Result(hdr, _) => hdr.has_cbt(),
+            SrdMessage::Challenge(hdr, _) => hdr.has_cbt(),
         }
     }
 
@@ -68,6 +83,9 @@ impl SrdMessage {
             SrdMessage::Accept(hdr, _) => hdr.has_mac(),
             SrdMessage::Confirm(hdr, _) => hdr.has_mac(),
             SrdMessage::Delegate(hdr, _) => hdr.has_mac(),
+            SrdMessage::Error(hdr, _) => hdr.has_mac(),
+            SrdMessage::Result(hdr, _) => hdr.has_mac(),
+            SrdMessage::Challenge(hdr, _) => hdr.has_mac(),
         }
     }
 
@@ -78,6 +96,9 @@ impl SrdMessage {
             SrdMessage::Accept(hdr, _) => hdr.has_skip(),
             SrdMessage::Confirm(hdr, _) => hdr.has_skip(),
             SrdMessage::Delegate(hdr, _) => hdr.has_skip(),
+            SrdMessage::Error(hdr, _) => hdr.has_skip(),
+            SrdMessage::Result(hdr, _) => hdr.has_skip(),
+            SrdMessage::Challenge(hdr, _) => hdr.has_skip(),
         }
     }
 
@@ -88,6 +109,9 @@ impl SrdMessage {
             SrdMessage::Accept(_, accept) => Some(accept.mac()),
             SrdMessage::Confirm(_, confirm) => Some(confirm.mac()),
             SrdMessage::Delegate(_, delegate) => Some(delegate.mac()),
+            SrdMessage::Error(_, _) => None,
+            SrdMessage::Result(_, result) => Some(result.mac()),
+            SrdMessage::Challenge(_, challenge) => Some(challenge.mac()),
         }
     }
 
@@ -98,6 +122,15 @@ impl SrdMessage {
             SrdMessage::Accept(_, ref mut accept) => Ok(accept.set_mac(mac)),
             SrdMessage::Confirm(_, ref mut confirm) => Ok(confirm.set_mac(mac)),
             SrdMessage::Delegate(_, ref mut delegate) => Ok(delegate.set_mac(mac)),
+            SrdMessage::Error(_, _) => Err(SrdError::Proto("No mac on an error message".to_owned())),
+            SrdMessage::Result(_, ref mut result) => {
+                result.set_mac(mac);
+                Ok(())
+            }
+            SrdMessage::Challenge(_, ref mut challenge) => {
+                challenge.set_mac(mac);
+                Ok(())
+            }
         }
     }
 
@@ -111,6 +144,46 @@ impl SrdMessage {
         }
     }
 
+    // Only the initiate and offer messages carry a version. Version 1 is implied when there is none.
+    pub fn set_version(&mut self, version: u16) {
+        match self {
+            SrdMessage::Initiate(hdr, initiate) => {
+                hdr.add_version_flag();
+                initiate.set_version(version);
+            }
+            SrdMessage::Offer(hdr, offer) => {
+                hdr.add_version_flag();
+                offer.set_version(version);
+            }
+            _ => {}
+        }
+    }
+
+    // Asks for a signed offer in an initiate message, tells the offer is signed in an offer message
+    pub fn add_signature_flag(&mut self) {
+        match self {
+            SrdMessage::Initiate(hdr, _) => hdr.add_signature_flag(),
+            SrdMessage::Offer(hdr, _) => hdr.add_signature_flag(),
+            _ => {}
+        }
+    }
+
+    pub fn add_pake_flag(&mut self) {
+        match self {
+            SrdMessage::Initiate(hdr, _) => hdr.add_pake_flag(),
+            SrdMessage::Offer(hdr, _) => hdr.add_pake_flag(),
+            _ => {}
+        }
+    }
+
+    pub fn add_result_flag(&mut self) {
+        match self {
+            SrdMessage::Initiate(hdr, _) => hdr.add_result_flag(),
+            SrdMessage::Offer(hdr, _) => hdr.add_result_flag(),
+            _ => {}
+        }
+    }
+
     pub fn validate(self) -> Result<Self> {
         match &self {
             SrdMessage::Initiate(hdr, initiate) => {
@@ -143,6 +216,21 @@ impl SrdMessage {
                 // MAC has to be set
                 hdr.validate_flags(true)?;
             }
+
+            SrdMessage::Error(hdr, _error) => {
+                // No MAC in that message
+                hdr.validate_flags(false)?;
+            }
+
+            SrdMessage::Result(hdr, _result) => {
+                // MAC has to be set
+                hdr.validate_flags(true)?;
+            }
+
+            SrdMessage::Challenge(hdr, _challenge) => {
+                // MAC has to be set
+                hdr.validate_flags(true)?;
+            }
         }
         Ok(self)
     }
@@ -160,7 +248,18 @@ impl Message for SrdMessage {
                 Ok(SrdMessage::Initiate(header, initiate).validate()?)
             }
             srd_msg_id::SRD_OFFER_MSG_ID => {
-                let offer = SrdOffer::read_from(&mut reader)?;
+                // The offer layout depends on the key exchange chosen by the server
+                let mut offer = if header.has_x25519() {
+                    SrdOffer::read_x25519_from(&mut reader)?
+                } else {
+                    SrdOffer::read_from(&mut reader)?
+                };
+                if header.has_version() {
+                    offer.read_version_from(&mut reader)?;
+                }
+                if header.has_signature() {
+                    offer.read_signature_from(&mut reader)?;
+                }
                 Ok(SrdMessage::Offer(header, offer).validate()?)
             }
             srd_msg_id::SRD_ACCEPT_MSG_ID => {
@@ -175,6 +274,18 @@ impl Message for SrdMessage {
                 let delegate = SrdDelegate::read_from(&mut reader)?;
                 Ok(SrdMessage::Delegate(header, delegate).validate()?)
             }
+            srd_msg_id::SRD_ERROR_MSG_ID => {
+                let error = SrdErrorMsg::read_from(&mut reader)?;
+                Ok(SrdMessage::Error(header, error).validate()?)
+            }
+            srd_msg_id::SRD_RESULT_MSG_ID => {
+                let result = SrdResult::read_from(&mut reader)?;
+                Ok(SrdMessage::Result(header, result).validate()?)
+            }
+            srd_msg_id::SRD_CHALLENGE_MSG_ID => {
+                let challenge = SrdChallenge::read_from(&mut reader)?;
+                Ok(SrdMessage::Challenge(header, challenge).validate()?)
+            }
             _ => Err(SrdError::UnknownMsgType),
         }
     }
@@ -206,6 +317,21 @@ impl Message for SrdMessage {
                 delegate.write_to(&mut writer)?;
                 Ok(())
             }
+            SrdMessage::Error(hdr, error) => {
+                hdr.write_to(&mut writer)?;
+                error.write_to(&mut writer)?;
+                Ok(())
+            }
+            SrdMessage::Result(hdr, result) => {
+                hdr.write_to(&mut writer)?;
+                result.write_to(&mut writer)?;
+                Ok(())
+            }
+            SrdMessage::Challenge(hdr, challenge) => {
+                hdr.write_to(&mut writer)?;
+                challenge.write_to(&mut writer)?;
+                Ok(())
+            }
         }
     }
 }
@@ -1,7 +1,10 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use messages::{expand_start, srd_msg_id, Message, SrdHeader, SrdMessage};
+use messages::{expand_start, srd_msg_id, Message, SrdHeader, SrdMessage, SRD_VERSION_1};
 use std::io::{Read, Write};
 use Result;
+use SrdError;
+
+pub const X25519_KEY_SIZE: u16 = 32;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SrdOffer {
@@ -11,12 +14,81 @@ pub struct SrdOffer {
     pub prime: Vec<u8>,
     pub public_key: Vec<u8>,
     pub nonce: [u8; 32],
+    version: Option<u16>,
+    signature: Option<(u16, Vec<u8>)>,
 }
 
 impl SrdOffer {
     pub fn key_size(&self) -> u16 {
         self.key_size
     }
+
+    // Version 1 servers don't send a version
+    pub fn version(&self) -> u16 {
+        self.version.unwrap_or(SRD_VERSION_1)
+    }
+
+    pub fn set_version(&mut self, version: u16) {
+        self.version = Some(version);
+    }
+
+    // The version is appended after the nonce, only when SRD_FLAG_VERSION is set
+    pub fn read_version_from<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        self.version = Some(reader.read_u16::<LittleEndian>()?);
+        Ok(())
+    }
+
+    // Signature algorithm and signature of a signed offer
+    pub fn signature(&self) -> Option<(u16, &[u8])> {
+        self.signature.as_ref().map(|&(algorithm, ref signature)| (algorithm, signature.as_slice()))
+    }
+
+    pub fn set_signature(&mut self, algorithm: u16, signature: Vec<u8>) {
+        self.signature = Some((algorithm, signature));
+    }
+
+    // Size of the signature at the end of a signed offer
+    pub fn signature_size(&self) -> usize {
+        self.signature.as_ref().map_or(0, |(_, signature)| 4 + signature.len())
+    }
+
+    // The signature is the last field, only when SRD_FLAG_SIGNATURE is set, so what is signed is everything before it
+    pub fn read_signature_from<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        let algorithm = reader.read_u16::<LittleEndian>()?;
+        let size = reader.read_u16::<LittleEndian>()?;
+
+        let mut signature = vec![0u8; size as usize];
+        reader.read_exact(&mut signature)?;
+        self.signature = Some((algorithm, signature));
+        Ok(())
+    }
+
+    // With X25519, there is no generator and no prime: only the 32-byte public key
+    pub fn read_x25519_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let ciphers = reader.read_u32::<LittleEndian>()?;
+        let key_size = reader.read_u16::<LittleEndian>()?;
+
+        if key_size != X25519_KEY_SIZE {
+            return Err(SrdError::InvalidKeySize);
+        }
+
+        let mut public_key = vec![0u8; key_size as usize];
+        reader.read_exact(&mut public_key)?;
+
+        let mut nonce = [0u8; 32];
+        reader.read_exact(&mut nonce)?;
+
+        Ok(SrdOffer {
+            ciphers,
+            key_size,
+            generator: Vec::new(),
+            prime: Vec::new(),
+            public_key,
+            nonce,
+            version: None,
+            signature: None,
+        })
+    }
 }
 
 impl Message for SrdOffer {
@@ -44,6 +116,8 @@ impl Message for SrdOffer {
             prime,
             public_key,
             nonce,
+            version: None,
+            signature: None,
         })
     }
 
@@ -54,6 +128,14 @@ impl Message for SrdOffer {
         writer.write_all(&self.prime)?;
         writer.write_all(&self.public_key)?;
         writer.write_all(&self.nonce)?;
+        if let Some(version) = self.version {
+            writer.write_u16::<LittleEndian>(version)?;
+        }
+        if let Some((algorithm, ref signature)) = self.signature {
+            writer.write_u16::<LittleEndian>(algorithm)?;
+            writer.write_u16::<LittleEndian>(signature.len() as u16)?;
+            writer.write_all(signature)?;
+        }
 
         Ok(())
     }
@@ -70,8 +152,8 @@ pub fn new_srd_offer_msg(
     nonce: [u8; 32],
 ) -> SrdMessage {
     expand_start(&mut generator, 2);
-    expand_start(&mut prime, (key_size / 8) as usize);
-    expand_start(&mut public_key, (key_size / 8) as usize);
+    expand_start(&mut prime, key_size as usize);
+    expand_start(&mut public_key, key_size as usize);
 
     let hdr = SrdHeader::new(srd_msg_id::SRD_OFFER_MSG_ID, seq_num, use_cbt, false);
     let offer = SrdOffer {
@@ -81,13 +163,41 @@ pub fn new_srd_offer_msg(
         prime,
         public_key,
         nonce,
+        version: None,
+        signature: None,
+    };
+    SrdMessage::Offer(hdr, offer)
+}
+
+pub fn new_srd_x25519_offer_msg(
+    seq_num: u8,
+    use_cbt: bool,
+    ciphers: u32,
+    public_key: [u8; 32],
+    nonce: [u8; 32],
+) -> SrdMessage {
+    let mut hdr = SrdHeader::new(srd_msg_id::SRD_OFFER_MSG_ID, seq_num, use_cbt, false);
+    hdr.add_x25519_flag();
+
+    let offer = SrdOffer {
+        ciphers,
+        key_size: X25519_KEY_SIZE,
+        generator: Vec::new(),
+        prime: Vec::new(),
+        public_key: public_key.to_vec(),
+        nonce,
+        version: None,
+        signature: None,
     };
     SrdMessage::Offer(hdr, offer)
 }
 
 #[cfg(test)]
 mod test {
-    use messages::{new_srd_offer_msg, srd_msg_id::SRD_OFFER_MSG_ID, Message, SrdMessage, SRD_SIGNATURE};
+    use messages::{
+        new_srd_offer_msg, new_srd_x25519_offer_msg, srd_msg_id::SRD_OFFER_MSG_ID, Message, SrdMessage,
+        SRD_SIGNATURE, SRD_VERSION_1, SRD_VERSION_2,
+    };
     use std;
 
     #[test]
@@ -110,4 +220,84 @@ mod test {
             Err(_) => assert!(false),
         }
     }
+
+    #[test]
+    fn offer_padding() {
+        // Leading zero bytes of the prime and public key are dropped by to_bytes_be()
+        let msg = new_srd_offer_msg(1, false, 0, 256, vec![2], vec![0xffu8; 255], vec![1u8], [0u8; 32]);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), 8 + 4 + 2 + 2 + 256 + 256 + 32);
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        assert_eq!(SrdMessage::read_from(&mut cursor).unwrap(), msg);
+    }
+
+    #[test]
+    fn x25519_offer_encoding() {
+        let msg = new_srd_x25519_offer_msg(1, false, 0, [1u8; 32], [2u8; 32]);
+        match msg {
+            SrdMessage::Offer(ref hdr, _) => assert!(hdr.has_x25519()),
+            _ => assert!(false),
+        }
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), 8 + 4 + 2 + 32 + 32);
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        assert_eq!(SrdMessage::read_from(&mut cursor).unwrap(), msg);
+    }
+
+    #[test]
+    fn offer_version() {
+        let mut msg = new_srd_x25519_offer_msg(1, false, 0, [1u8; 32], [2u8; 32]);
+        match msg {
+            SrdMessage::Offer(_, ref offer) => assert_eq!(offer.version(), SRD_VERSION_1),
+            _ => assert!(false),
+        }
+
+        msg.set_version(SRD_VERSION_2);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), 8 + 4 + 2 + 32 + 32 + 2);
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        match SrdMessage::read_from(&mut cursor).unwrap() {
+            SrdMessage::Offer(hdr, offer) => {
+                assert!(hdr.has_version());
+                assert_eq!(offer.version(), SRD_VERSION_2);
+            }
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn signed_offer_encoding() {
+        let mut msg = new_srd_x25519_offer_msg(1, false, 0, [1u8; 32], [2u8; 32]);
+        msg.set_version(SRD_VERSION_2);
+        match msg {
+            SrdMessage::Offer(ref mut hdr, ref mut offer) => {
+                hdr.add_signature_flag();
+                offer.set_signature(1, vec![3u8; 64]);
+            }
+            _ => assert!(false),
+        }
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), 8 + 4 + 2 + 32 + 32 + 2 + 4 + 64);
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        match SrdMessage::read_from(&mut cursor).unwrap() {
+            SrdMessage::Offer(hdr, offer) => {
+                assert!(hdr.has_signature());
+                assert_eq!(offer.signature(), Some((1, &[3u8; 64][..])));
+                assert_eq!(offer.signature_size(), 4 + 64);
+            }
+            _ => assert!(false),
+        }
+    }
 }
@@ -0,0 +1,77 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use messages::{srd_message::ReadMac, srd_msg_id, Message, SrdHeader, SrdMessage};
+use Result;
+
+// Status of accepted credentials. Anything else is the code of the error, like in an error message.
+pub const SRD_RESULT_SUCCESS: u16 = 0;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SrdResult {
+    pub status: u16,
+    reserved: u16,
+    mac: [u8; 32],
+}
+
+impl SrdResult {
+    pub fn mac(&self) -> &[u8] {
+        &self.mac
+    }
+
+    pub fn set_mac(&mut self, mac: &[u8]) {
+        self.mac.clone_from_slice(mac);
+    }
+}
+
+impl Message for SrdResult {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self>
+        where
+            Self: Sized,
+    {
+        let status = reader.read_u16::<LittleEndian>()?;
+        let reserved = reader.read_u16::<LittleEndian>()?;
+
+        let mut mac = [0u8; 32];
+        reader.read_mac(&mut mac)?;
+
+        Ok(SrdResult { status, reserved, mac })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<LittleEndian>(self.status)?;
+        writer.write_u16::<LittleEndian>(self.reserved)?;
+        writer.write_all(&self.mac)?;
+        Ok(())
+    }
+}
+
+pub fn new_srd_result_msg(seq_num: u8, use_cbt: bool, status: u16) -> SrdMessage {
+    let hdr = SrdHeader::new(srd_msg_id::SRD_RESULT_MSG_ID, seq_num, use_cbt, true);
+    let result = SrdResult {
+        status,
+        reserved: 0,
+        mac: [0u8; 32],
+    };
+
+    SrdMessage::Result(hdr, result)
+}
+
+#[cfg(test)]
+mod test {
+    use messages::{new_srd_result_msg, srd_msg_id::SRD_RESULT_MSG_ID, Message, SrdMessage, SRD_RESULT_SUCCESS};
+    use std;
+
+    #[test]
+    fn result_encoding() {
+        let msg = new_srd_result_msg(5, false, SRD_RESULT_SUCCESS);
+        assert_eq!(msg.msg_type(), SRD_RESULT_MSG_ID);
+        assert!(msg.has_mac());
+
+        let mut buffer: Vec<u8> = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+
+        let mut cursor = std::io::Cursor::new(buffer.as_slice());
+        assert_eq!(SrdMessage::read_from(&mut cursor).unwrap(), msg);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use srd_errors::SrdError;
+use Result;
+
+// Client nonces already used with a server. A cache is shared by all the contexts of a server, so a client nonce
+// can't be used twice, even with server state restored from a previous session.
+pub trait NonceCache: Send + Sync {
+    // Fails with ReplayDetected if the nonce has already been seen, or with another error if it can't be recorded
+    fn insert(&self, nonce: &[u8; 32]) -> Result<()>;
+}
+
+// In-memory cache keeping nonces for a time window. Nonces are never dropped before the end of their window: when the
+// cache is full, new nonces are refused until the oldest ones expire, so the capacity has to cover the handshakes
+// expected in a window.
+pub struct MemoryNonceCache {
+    capacity: usize,
+    window: Duration,
+    nonces: Mutex<Nonces>,
+}
+
+struct Nonces {
+    seen: HashSet<[u8; 32]>,
+    by_age: VecDeque<(Instant, [u8; 32])>,
+}
+
+impl MemoryNonceCache {
+    pub fn new(capacity: usize, window: Duration) -> Result<MemoryNonceCache> {
+        if capacity == 0 {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        Ok(MemoryNonceCache {
+            capacity,
+            window,
+            nonces: Mutex::new(Nonces {
+                seen: HashSet::new(),
+                by_age: VecDeque::new(),
+            }),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Inserts a nonce seen at a given time
+    fn insert_at(&self, nonce: &[u8; 32], now: Instant) -> Result<()> {
+        let mut nonces = self.lock();
+
+        while let Some(&(time, oldest)) = nonces.by_age.front() {
+            if now.saturating_duration_since(time) < self.window {
+                break;
+            }
+            nonces.seen.remove(&oldest);
+            nonces.by_age.pop_front();
+        }
+
+        if nonces.seen.contains(nonce) {
+            return Err(SrdError::ReplayDetected);
+        }
+
+        // Dropping a nonce still in its window would let it be replayed
+        if nonces.by_age.len() >= self.capacity {
+            return Err(SrdError::Internal("Nonce cache full".to_owned()));
+        }
+
+        nonces.seen.insert(*nonce);
+        nonces.by_age.push_back((now, *nonce));
+        Ok(())
+    }
+
+    // A panic while the lock was held can't leave the nonces inconsistent
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Nonces> {
+        match self.nonces.lock() {
+            Ok(nonces) => nonces,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl NonceCache for MemoryNonceCache {
+    fn insert(&self, nonce: &[u8; 32]) -> Result<()> {
+        self.insert_at(nonce, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nonce_cache::MemoryNonceCache;
+    use srd_errors::SrdError;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn memory_nonce_cache() {
+        assert!(MemoryNonceCache::new(0, Duration::from_secs(60)).is_err());
+
+        let window = Duration::from_secs(60);
+        let cache = MemoryNonceCache::new(2, window).unwrap();
+        let now = Instant::now();
+
+        cache.insert_at(&[1; 32], now).unwrap();
+        match cache.insert_at(&[1; 32], now) {
+            Err(SrdError::ReplayDetected) => {}
+            _ => assert!(false),
+        }
+        cache.insert_at(&[2; 32], now + window / 2).unwrap();
+        assert_eq!(cache.len(), 2);
+
+        // Full: nothing is forgotten before the end of its window
+        match cache.insert_at(&[3; 32], now + window / 2) {
+            Err(SrdError::Internal(_)) => {}
+            _ => assert!(false),
+        }
+        match cache.insert_at(&[1; 32], now + window / 2) {
+            Err(SrdError::ReplayDetected) => {}
+            _ => assert!(false),
+        }
+        assert_eq!(cache.len(), 2);
+
+        // Once the oldest nonce expires, there is room again and it can be used again
+        cache.insert_at(&[3; 32], now + window).unwrap();
+        cache.insert_at(&[1; 32], now + window + window / 2).unwrap();
+        match cache.insert_at(&[2; 32], now + window + window / 2) {
+            Err(SrdError::Internal(_)) => {}
+            _ => assert!(false),
+        }
+    }
+}
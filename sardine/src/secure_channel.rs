@@ -0,0 +1,168 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Write;
+
+use cipher::Cipher;
+use srd_errors::SrdError;
+use Result;
+
+// Record: sequence number (8 bytes), size of the encrypted data (4 bytes), encrypted data
+pub const RECORD_HEADER_SIZE: usize = 12;
+
+// Encrypts and authenticates the messages exchanged once the handshake is complete. Each direction has its own key and
+// IV, and the nonce of a record is the IV combined with its sequence number, so a record can't be replayed, reordered,
+// dropped or sent back to its sender without open() failing. It isn't serializable: a restored copy would reuse nonces.
+pub struct SecureChannel {
+    cipher: Cipher,
+    send_key: [u8; 32],
+    send_iv: [u8; 32],
+    send_seq_num: u64,
+    recv_key: [u8; 32],
+    recv_iv: [u8; 32],
+    recv_seq_num: u64,
+}
+
+impl SecureChannel {
+    pub(crate) fn new(
+        cipher: Cipher,
+        send_key: [u8; 32],
+        send_iv: [u8; 32],
+        recv_key: [u8; 32],
+        recv_iv: [u8; 32],
+    ) -> SecureChannel {
+        SecureChannel {
+            cipher,
+            send_key,
+            send_iv,
+            send_seq_num: 0,
+            recv_key,
+            recv_iv,
+            recv_seq_num: 0,
+        }
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    // Size of the record sealing data_size bytes
+    pub fn record_size(&self, data_size: usize) -> usize {
+        RECORD_HEADER_SIZE + data_size + self.cipher.tag_size()
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.send_seq_num == u64::MAX {
+            return Err(SrdError::BadSequence);
+        }
+
+        let encrypted_data = self
+            .cipher
+            .encrypt_data(data, &self.send_key, &nonce(&self.send_iv, self.send_seq_num))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + encrypted_data.len());
+        record.write_u64::<LittleEndian>(self.send_seq_num)?;
+        record.write_u32::<LittleEndian>(encrypted_data.len() as u32)?;
+        record.write_all(&encrypted_data)?;
+
+        self.send_seq_num += 1;
+        Ok(record)
+    }
+
+    // Records have to be opened in the order they were sealed. A record that can't be opened doesn't change the
+    // channel, so it can be dropped and the channel used again.
+    pub fn open(&mut self, record: &[u8]) -> Result<Vec<u8>> {
+        if record.len() < RECORD_HEADER_SIZE {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        let mut header = &record[..RECORD_HEADER_SIZE];
+        let seq_num = header.read_u64::<LittleEndian>()?;
+        let size = header.read_u32::<LittleEndian>()? as usize;
+
+        if size != record.len() - RECORD_HEADER_SIZE || size < self.cipher.tag_size() {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        if seq_num != self.recv_seq_num || seq_num == u64::MAX {
+            return Err(SrdError::BadSequence);
+        }
+
+        let data = self.cipher.decrypt_data(
+            &record[RECORD_HEADER_SIZE..],
+            &self.recv_key,
+            &nonce(&self.recv_iv, seq_num),
+        )?;
+
+        self.recv_seq_num += 1;
+        Ok(data)
+    }
+}
+
+// The sequence number is xored in the first 12 bytes of the IV, used by every AEAD cipher
+fn nonce(iv: &[u8; 32], seq_num: u64) -> [u8; 32] {
+    let mut nonce = *iv;
+    for (n, s) in nonce[4..12].iter_mut().zip(seq_num.to_be_bytes().iter()) {
+        *n ^= s;
+    }
+    nonce
+}
+
+#[cfg(test)]
+mod test {
+    use cipher::Cipher;
+    use secure_channel::SecureChannel;
+    use SrdError;
+
+    fn channels() -> (SecureChannel, SecureChannel) {
+        let a = SecureChannel::new(Cipher::ChaCha20Poly1305, [1; 32], [2; 32], [3; 32], [4; 32]);
+        let b = SecureChannel::new(Cipher::ChaCha20Poly1305, [3; 32], [4; 32], [1; 32], [2; 32]);
+        (a, b)
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (mut a, mut b) = channels();
+
+        for i in 0..3u8 {
+            let record = a.seal(&[i; 10]).unwrap();
+            assert_eq!(record.len(), a.record_size(10));
+            assert_eq!(b.open(&record).unwrap(), vec![i; 10]);
+
+            let record = b.seal(&[]).unwrap();
+            assert_eq!(a.open(&record).unwrap(), Vec::<u8>::new());
+        }
+    }
+
+    #[test]
+    fn replay_and_reordering() {
+        let (mut a, mut b) = channels();
+
+        let first = a.seal(b"first").unwrap();
+        let second = a.seal(b"second").unwrap();
+
+        match b.open(&second) {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+        b.open(&first).unwrap();
+        match b.open(&first) {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+
+        // Sent back to its sender
+        assert!(a.open(&second).is_err());
+
+        // The sequence number is authenticated
+        let mut third = a.seal(b"third").unwrap();
+        third[0] = 1;
+        match b.open(&third) {
+            Err(SrdError::Crypto) => {}
+            _ => assert!(false),
+        }
+
+        let mut modified = second.clone();
+        *modified.last_mut().unwrap() ^= 1;
+        assert!(b.open(&modified).is_err());
+        assert_eq!(b.open(&second).unwrap(), b"second".to_vec());
+    }
+}
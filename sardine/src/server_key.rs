@@ -0,0 +1,198 @@
+use ed25519_dalek;
+use ed25519_dalek::{Signer, Verifier};
+use p256::ecdsa;
+
+use srd_errors::SrdError;
+use Result;
+
+const ED25519_ID: u16 = 1;
+const ECDSA_P256_ID: u16 = 2;
+
+// Context mixed in the signed data, so an offer signature can't be used for anything else
+pub(crate) const OFFER_SIGNATURE_CONTEXT: &[u8] = b"SRD offer signature";
+
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Ed25519,
+    EcdsaP256,
+}
+
+impl SignatureAlgorithm {
+    pub fn id(&self) -> u16 {
+        match *self {
+            SignatureAlgorithm::Ed25519 => ED25519_ID,
+            SignatureAlgorithm::EcdsaP256 => ECDSA_P256_ID,
+        }
+    }
+
+    pub fn from_id(id: u16) -> Option<SignatureAlgorithm> {
+        match id {
+            ED25519_ID => Some(SignatureAlgorithm::Ed25519),
+            ECDSA_P256_ID => Some(SignatureAlgorithm::EcdsaP256),
+            _ => None,
+        }
+    }
+}
+
+// Long-term key a server signs its offer with. Ed25519 keys are the 32-byte seed, P-256 keys the 32-byte scalar.
+// It is never serialized.
+#[derive(Clone)]
+pub struct ServerSigningKey {
+    algorithm: SignatureAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl ServerSigningKey {
+    pub fn new(algorithm: SignatureAlgorithm, secret: &[u8]) -> Result<ServerSigningKey> {
+        let key = ServerSigningKey {
+            algorithm,
+            secret: secret.to_vec(),
+        };
+
+        // Make sure the key can be used now rather than during a handshake
+        key.public_key()?;
+        Ok(key)
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+
+    pub fn public_key(&self) -> Result<ServerPublicKey> {
+        let public_key = match self.algorithm {
+            SignatureAlgorithm::Ed25519 => self.ed25519()?.verifying_key().to_bytes().to_vec(),
+            SignatureAlgorithm::EcdsaP256 => self
+                .ecdsa_p256()?
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec(),
+        };
+
+        Ok(ServerPublicKey {
+            algorithm: self.algorithm,
+            public_key,
+        })
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let signature = match self.algorithm {
+            SignatureAlgorithm::Ed25519 => self.ed25519()?.sign(data).to_bytes().to_vec(),
+            SignatureAlgorithm::EcdsaP256 => {
+                let signature: ecdsa::Signature = self.ecdsa_p256()?.sign(data);
+                signature.to_bytes().to_vec()
+            }
+        };
+        Ok(signature)
+    }
+
+    fn ed25519(&self) -> Result<ed25519_dalek::SigningKey> {
+        if self.secret.len() != ed25519_dalek::SECRET_KEY_LENGTH {
+            return Err(SrdError::InvalidKeySize);
+        }
+
+        let mut secret = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+        secret.copy_from_slice(&self.secret);
+        Ok(ed25519_dalek::SigningKey::from_bytes(&secret))
+    }
+
+    fn ecdsa_p256(&self) -> Result<ecdsa::SigningKey> {
+        ecdsa::SigningKey::from_slice(&self.secret).map_err(|_| SrdError::Crypto)
+    }
+}
+
+// Server key pinned by a client. Ed25519 keys are 32 bytes, P-256 keys are SEC1 encoded, compressed or not.
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerPublicKey {
+    algorithm: SignatureAlgorithm,
+    public_key: Vec<u8>,
+}
+
+impl ServerPublicKey {
+    pub fn new(algorithm: SignatureAlgorithm, public_key: &[u8]) -> Result<ServerPublicKey> {
+        let key = ServerPublicKey {
+            algorithm,
+            public_key: public_key.to_vec(),
+        };
+
+        match algorithm {
+            SignatureAlgorithm::Ed25519 => key.ed25519().map(|_| ())?,
+            SignatureAlgorithm::EcdsaP256 => key.ecdsa_p256().map(|_| ())?,
+        }
+        Ok(key)
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
+        match self.algorithm {
+            SignatureAlgorithm::Ed25519 => {
+                let signature =
+                    ed25519_dalek::Signature::from_slice(signature).map_err(|_| SrdError::UntrustedServer)?;
+                self.ed25519()?
+                    .verify_strict(data, &signature)
+                    .map_err(|_| SrdError::UntrustedServer)
+            }
+            SignatureAlgorithm::EcdsaP256 => {
+                let signature = ecdsa::Signature::from_slice(signature).map_err(|_| SrdError::UntrustedServer)?;
+                self.ecdsa_p256()?
+                    .verify(data, &signature)
+                    .map_err(|_| SrdError::UntrustedServer)
+            }
+        }
+    }
+
+    fn ed25519(&self) -> Result<ed25519_dalek::VerifyingKey> {
+        if self.public_key.len() != ed25519_dalek::PUBLIC_KEY_LENGTH {
+            return Err(SrdError::InvalidPublicKey);
+        }
+
+        let mut public_key = [0u8; ed25519_dalek::PUBLIC_KEY_LENGTH];
+        public_key.copy_from_slice(&self.public_key);
+        ed25519_dalek::VerifyingKey::from_bytes(&public_key).map_err(|_| SrdError::InvalidPublicKey)
+    }
+
+    fn ecdsa_p256(&self) -> Result<ecdsa::VerifyingKey> {
+        ecdsa::VerifyingKey::from_sec1_bytes(&self.public_key).map_err(|_| SrdError::InvalidPublicKey)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use server_key::{ServerPublicKey, ServerSigningKey, SignatureAlgorithm};
+    use SrdError;
+
+    #[test]
+    fn sign_and_verify() {
+        for algorithm in vec![SignatureAlgorithm::Ed25519, SignatureAlgorithm::EcdsaP256] {
+            let key = ServerSigningKey::new(algorithm, &[3u8; 32]).unwrap();
+            let public_key = key.public_key().unwrap();
+            let signature = key.sign(b"offer").unwrap();
+
+            public_key.verify(b"offer", &signature).unwrap();
+            match public_key.verify(b"other offer", &signature) {
+                Err(SrdError::UntrustedServer) => {}
+                _ => assert!(false),
+            }
+
+            let other_key = ServerSigningKey::new(algorithm, &[4u8; 32]).unwrap();
+            assert!(other_key.public_key().unwrap().verify(b"offer", &signature).is_err());
+
+            let public_key = ServerPublicKey::new(algorithm, public_key.as_bytes()).unwrap();
+            public_key.verify(b"offer", &signature).unwrap();
+        }
+
+        assert!(ServerSigningKey::new(SignatureAlgorithm::Ed25519, &[3u8; 31]).is_err());
+        assert!(ServerSigningKey::new(SignatureAlgorithm::EcdsaP256, &[0u8; 32]).is_err());
+        assert!(ServerPublicKey::new(SignatureAlgorithm::EcdsaP256, &[7u8; 33]).is_err());
+        assert!(ServerPublicKey::new(SignatureAlgorithm::Ed25519, &[5u8; 33]).is_err());
+    }
+}
@@ -1,20 +1,30 @@
 use std;
 use std::io::Write;
 
+use byteorder::{LittleEndian, WriteBytesExt};
 use rand::{rngs::OsRng, RngCore};
 
 use num_bigint::BigUint;
 
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+use hkdf::Hkdf;
 use hmac::{Hmac, Mac, NewMac};
 use sha2::{Digest, Sha256};
 
+use channel_binding::ChannelBinding;
 use cipher::Cipher;
+use credential_validator::CredentialValidator;
 use Result;
 
 use blobs::{Blob, SrdBlob};
 use dh_params::SRD_DH_PARAMS;
 use messages::*;
+use secure_channel::SecureChannel;
+use server_key::{ServerSigningKey, SignatureAlgorithm, OFFER_SIGNATURE_CONTEXT};
+use srd_config::{validate_ciphers, validate_key_size, CbtPolicy, SrdConfig};
 use srd_errors::SrdError;
+use transcript::Transcript;
 
 cfg_if! {
     if #[cfg(feature = "wasm")] {
@@ -87,6 +97,40 @@ cfg_if! {
             pub fn set_cert_data(&mut self, buffer: Vec<u8>) {
                 self._set_cert_data(buffer).unwrap();
             }
+
+            pub fn set_key_size(&mut self, key_size: u16) -> std::result::Result<(), JsValue> {
+                self._set_key_size(key_size).map_err(|e| JsValue::from_str(&e.to_string()))
+            }
+
+            pub fn set_cbt_policy(&mut self, cbt_policy: CbtPolicy) -> std::result::Result<(), JsValue> {
+                self._set_cbt_policy(cbt_policy).map_err(|e| JsValue::from_str(&e.to_string()))
+            }
+
+            pub fn set_channel_binding(
+                &mut self,
+                channel_binding: ChannelBinding,
+                data: Vec<u8>,
+            ) -> std::result::Result<(), JsValue> {
+                self._set_channel_binding(channel_binding, data)
+                    .map_err(|e| JsValue::from_str(&e.to_string()))
+            }
+
+            pub fn export_keying_material(
+                &self,
+                label: &str,
+                context: &[u8],
+                len: usize,
+            ) -> std::result::Result<Vec<u8>, JsValue> {
+                self._export_keying_material(label, context, len)
+                    .map_err(|e| JsValue::from_str(&e.to_string()))
+            }
+
+            pub fn send_result(&mut self, accepted: bool) -> std::result::Result<Vec<u8>, JsValue> {
+                let mut output_data = Vec::new();
+                self._send_result(accepted, &mut output_data)
+                    .map_err(|e| JsValue::from_str(&e.to_string()))?;
+                Ok(output_data)
+            }
         }
     }
     else {
@@ -122,6 +166,27 @@ cfg_if! {
                 Ok(())
             }
 
+            pub fn set_key_size(&mut self, key_size: u16) -> Result<()> {
+                self._set_key_size(key_size)
+            }
+
+            pub fn set_cbt_policy(&mut self, cbt_policy: CbtPolicy) -> Result<()> {
+                self._set_cbt_policy(cbt_policy)
+            }
+
+            pub fn set_channel_binding(&mut self, channel_binding: ChannelBinding, data: Vec<u8>) -> Result<()> {
+                self._set_channel_binding(channel_binding, data)
+            }
+
+            pub fn export_keying_material(&self, label: &str, context: &[u8], len: usize) -> Result<Vec<u8>> {
+                self._export_keying_material(label, context, len)
+            }
+
+            // With a result message, a server tells the client whether the credentials it delegated are accepted
+            pub fn send_result(&mut self, accepted: bool, output_data: &mut Vec<u8>) -> Result<()> {
+                self._send_result(accepted, output_data)
+            }
+
             pub fn get_output_data(&self) -> &Option<Vec<u8>> {
                 &self.output_data
             }
@@ -134,22 +199,102 @@ cfg_if! {
     }
 }
 
+// Position of a context in the SRD handshake.
+//
+// Client: `Initial` -> `AwaitingOffer` -> `AwaitingConfirm` -> `AwaitingResult` -> `Complete`
+//
+// Server: `Initial` -> `AwaitingAccept` -> `AwaitingDelegate` -> `AwaitingVerdict` -> `Complete`
+// (`AwaitingDelegate` is skipped when delegation is skipped, `AwaitingResult` and `AwaitingVerdict` when there is no
+// result message)
+//
+// New states are added at the end, so the values seen through FFI don't change.
+//
+// Any error during the handshake moves the context to `Failed`, which is final: a failed context can't be reused.
+// Input after `Complete` is rejected without touching the session.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SrdState {
+    Initial,
+    AwaitingOffer,
+    AwaitingAccept,
+    AwaitingConfirm,
+    AwaitingDelegate,
+    Complete,
+    Failed,
+    AwaitingResult,
+    AwaitingVerdict,
+}
+
+// Key agreement negotiated during the handshake. X25519 is only used when both sides enable it.
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyExchange {
+    Dh,
+    X25519,
+}
+
+// What has been negotiated, once the handshake is complete
+#[derive(Clone)]
+pub struct SessionInfo {
+    cipher: Cipher,
+    key_size: u16,
+    use_cbt: bool,
+    blob: Option<SrdBlob>,
+}
+
+impl SessionInfo {
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn key_size(&self) -> u16 {
+        self.key_size
+    }
+
+    pub fn use_cbt(&self) -> bool {
+        self.use_cbt
+    }
+
+    // Credentials delegated by the client, on the server side. Blobs sent after a challenge are in Srd::get_blob().
+    pub fn blob(&self) -> Option<&SrdBlob> {
+        self.blob.as_ref()
+    }
+}
+
+// What to do after a step of the handshake
+#[derive(Clone)]
+pub enum SrdOutcome {
+    // Send the message and wait for the answer
+    Send(Vec<u8>),
+    // Send the last message, nothing comes back: the handshake is complete
+    SendAndComplete(Vec<u8>, SessionInfo),
+    Complete(SessionInfo),
+    // Server with a result message and no validator: the credentials have to be accepted or rejected with send_result()
+    AwaitingVerdict,
+}
+
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
 pub struct Srd {
     blob: Option<SrdBlob>,
+    extra_blobs: Vec<SrdBlob>,
     output_data: Option<Vec<u8>>,
 
-    is_server: bool,
-    skip_delegation: bool,
+    config: SrdConfig,
+
     key_size: u16,
     seq_num: u8,
-    state: u8,
+    state: SrdState,
+    version: u16,
 
-    messages: Vec<Vec<u8>>,
+    transcript: Transcript,
 
-    cert_data: Option<Vec<u8>>,
     use_cbt: bool,
+    use_result: bool,
+    challenge_count: usize,
+    // The channel keys and nonces can only be used once
+    channel_taken: bool,
 
     client_nonce: [u8; 32],
     server_nonce: [u8; 32],
@@ -157,49 +302,74 @@ pub struct Srd {
     integrity_key: [u8; 32],
     iv: [u8; 32],
 
-    supported_ciphers: Vec<Cipher>,
     cipher: Cipher,
 
     generator: BigUint,
 
     prime: BigUint,
     private_key: BigUint,
+    key_exchange: KeyExchange,
+    x25519_private_key: [u8; 32],
     secret_key: Vec<u8>,
+
+    #[cfg_attr(feature = "ser", serde(skip))]
+    validator: Option<Box<dyn CredentialValidator>>,
 }
 
+// A client doesn't answer more challenges than this, so a server can't keep it busy
+const MAX_CHALLENGES: usize = 16;
+
 // Same implementation, both public
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl Srd {
     pub fn set_raw_blob(&mut self, blob: SrdBlob) {
         self.blob = Some(blob);
     }
+
+    // Blob a client sends if the server asks for its type with a challenge
+    pub fn add_raw_blob(&mut self, blob: SrdBlob) {
+        self.extra_blobs.push(blob);
+    }
+
+    pub fn state(&self) -> SrdState {
+        self.state
+    }
+
+    pub fn get_key_size(&self) -> u16 {
+        self.key_size
+    }
+
+    // Protocol version in use. Before the offer, this is the highest version supported.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
 }
 
 impl Srd {
-    fn _new(is_server: bool, skip_delegation: bool) -> Srd {
-        let supported_ciphers;
-        if cfg!(feature = "fips") {
-            supported_ciphers = vec![Cipher::AES256];
-        } else if cfg!(feature = "aes") {
-            supported_ciphers = vec![Cipher::XChaCha20, Cipher::ChaCha20, Cipher::AES256];
-        } else {
-            supported_ciphers = vec![Cipher::XChaCha20, Cipher::ChaCha20];
-        }
+    pub fn from_config(config: SrdConfig) -> Srd {
+        let key_size = config.key_size;
+        let use_cbt = config.use_cbt();
+        let use_result = config.result_message && !config.skip_delegation;
+        let version = config.max_version;
 
         Srd {
             blob: None,
+            extra_blobs: Vec::new(),
             output_data: None,
 
-            is_server,
-            skip_delegation,
-            key_size: 256,
+            config,
+
+            key_size,
             seq_num: 0,
-            state: 0,
+            state: SrdState::Initial,
+            version,
 
-            messages: Vec::new(),
+            transcript: Transcript::new(),
 
-            cert_data: None,
-            use_cbt: false,
+            use_cbt,
+            use_result,
+            challenge_count: 0,
+            channel_taken: false,
 
             client_nonce: [0; 32],
             server_nonce: [0; 32],
@@ -207,78 +377,245 @@ impl Srd {
             integrity_key: [0; 32],
             iv: [0; 32],
 
-            supported_ciphers,
             cipher: Cipher::XChaCha20,
 
             generator: BigUint::from_bytes_be(&[0]),
 
             prime: BigUint::from_bytes_be(&[0]),
             private_key: BigUint::from_bytes_be(&[0]),
+            key_exchange: KeyExchange::Dh,
+            x25519_private_key: [0; 32],
             secret_key: Vec::new(),
+
+            validator: None,
         }
     }
 
+    fn _new(is_server: bool, skip_delegation: bool) -> Srd {
+        let mut config = SrdConfig::new(is_server);
+        config.skip_delegation = skip_delegation;
+        Srd::from_config(config)
+    }
+
     fn _authenticate(&mut self, input_data: &[u8], output_data: &mut Vec<u8>) -> Result<bool> {
         // We don't want anybody to access previous output_data.
         self.output_data = None;
 
-        if self.is_server {
-            match self.state {
-                0 => self.server_authenticate_0(input_data, output_data)?,
-                1 => {
-                    self.server_authenticate_1(input_data, output_data)?;
-                    if self.skip_delegation {
-                        self.state += 1;
-                        return Ok(true);
-                    }
+        // A stray message after the handshake is rejected, but the session stays usable
+        if self.state == SrdState::Complete {
+            return Err(SrdError::BadSequence);
+        }
+
+        // A failure while the peer waits for an answer is reported to it, unless the peer is the one reporting one.
+        // Nothing is expected after the result message either.
+        let answer_error = !input_data.is_empty()
+            && self.state != SrdState::Failed
+            && !is_final_msg(input_data);
+
+        let result = if self.config.is_server {
+            self.server_authenticate(input_data, output_data)
+        } else {
+            self.client_authenticate(input_data, output_data)
+        };
+
+        // Once failed, the context is poisoned and every following call is rejected
+        match result.and_then(|next_state| self.transition(next_state)) {
+            Ok(()) => Ok(self.state == SrdState::Complete),
+            Err(e) => {
+                self.state = SrdState::Failed;
+                // A result message already tells the peer why
+                if answer_error && output_data.is_empty() {
+                    new_srd_error_msg(self.seq_num, &e).write_to(output_data)?;
                 }
-                2 => {
-                    self.server_authenticate_2(input_data)?;
-                    self.state += 1;
-                    return Ok(true);
+                Err(e)
+            }
+        }
+    }
+
+    fn client_authenticate(&mut self, input_data: &[u8], output_data: &mut Vec<u8>) -> Result<SrdState> {
+        match self.state {
+            SrdState::Initial => {
+                self.client_authenticate_0(output_data)?;
+                Ok(SrdState::AwaitingOffer)
+            }
+            SrdState::AwaitingOffer => {
+                self.client_authenticate_1(input_data, output_data)?;
+                Ok(SrdState::AwaitingConfirm)
+            }
+            SrdState::AwaitingConfirm => {
+                self.client_authenticate_2(input_data, output_data)?;
+                if self.use_result {
+                    Ok(SrdState::AwaitingResult)
+                } else {
+                    Ok(SrdState::Complete)
                 }
-                _ => return Err(SrdError::BadSequence),
             }
-        } else {
-            match self.state {
-                0 => self.client_authenticate_0(output_data)?,
-                1 => self.client_authenticate_1(input_data, output_data)?,
-                2 => {
-                    self.client_authenticate_2(input_data, output_data)?;
-                    self.state += 1;
-                    return Ok(true);
+            SrdState::AwaitingResult => {
+                if self.client_authenticate_3(input_data, output_data)? {
+                    Ok(SrdState::AwaitingResult)
+                } else {
+                    Ok(SrdState::Complete)
+                }
+            }
+            _ => Err(SrdError::BadSequence),
+        }
+    }
+
+    fn server_authenticate(&mut self, input_data: &[u8], output_data: &mut Vec<u8>) -> Result<SrdState> {
+        match self.state {
+            SrdState::Initial => {
+                self.server_authenticate_0(input_data, output_data)?;
+                Ok(SrdState::AwaitingAccept)
+            }
+            SrdState::AwaitingAccept => {
+                self.server_authenticate_1(input_data, output_data)?;
+                if self.config.skip_delegation {
+                    Ok(SrdState::Complete)
+                } else {
+                    Ok(SrdState::AwaitingDelegate)
+                }
+            }
+            SrdState::AwaitingDelegate => {
+                if self.server_authenticate_2(input_data, output_data)? {
+                    Ok(SrdState::AwaitingDelegate)
+                } else if self.use_result && self.validator.is_none() {
+                    Ok(SrdState::AwaitingVerdict)
+                } else {
+                    Ok(SrdState::Complete)
                 }
-                _ => return Err(SrdError::BadSequence),
             }
+            _ => Err(SrdError::BadSequence),
         }
-        self.state += 1;
-        Ok(false)
+    }
+
+    // All the state changes go through here so an illegal transition can't happen silently
+    fn transition(&mut self, next_state: SrdState) -> Result<()> {
+        let allowed = match (self.config.is_server, self.state, next_state) {
+            (false, SrdState::Initial, SrdState::AwaitingOffer) => true,
+            (false, SrdState::AwaitingOffer, SrdState::AwaitingConfirm) => true,
+            (false, SrdState::AwaitingConfirm, SrdState::Complete) => !self.use_result,
+            (false, SrdState::AwaitingConfirm, SrdState::AwaitingResult) => self.use_result,
+            (false, SrdState::AwaitingResult, SrdState::AwaitingResult) => true,
+            (false, SrdState::AwaitingResult, SrdState::Complete) => true,
+            (true, SrdState::Initial, SrdState::AwaitingAccept) => true,
+            (true, SrdState::AwaitingAccept, SrdState::AwaitingDelegate) => !self.config.skip_delegation,
+            (true, SrdState::AwaitingAccept, SrdState::Complete) => self.config.skip_delegation,
+            (true, SrdState::AwaitingDelegate, SrdState::AwaitingDelegate) => !self.config.challenges.is_empty(),
+            (true, SrdState::AwaitingDelegate, SrdState::Complete) => !self.use_result || self.validator.is_some(),
+            (true, SrdState::AwaitingDelegate, SrdState::AwaitingVerdict) => {
+                self.use_result && self.validator.is_none()
+            }
+            (true, SrdState::AwaitingVerdict, SrdState::Complete) => true,
+            _ => false,
+        };
+
+        if !allowed {
+            return Err(SrdError::BadSequence);
+        }
+
+        self.state = next_state;
+        Ok(())
     }
 
     fn _set_cert_data(&mut self, buffer: Vec<u8>) -> Result<()> {
-        self.cert_data = Some(buffer);
-        self.use_cbt = true;
+        self.config.cert_data = Some(buffer);
+        self.use_cbt = self.config.use_cbt();
         Ok(())
     }
 
-    pub fn set_ciphers(&mut self, ciphers: Vec<Cipher>) -> Result<()> {
-        if cfg!(feature = "fips") {
-            return Err(SrdError::Cipher);
+    fn _set_channel_binding(&mut self, channel_binding: ChannelBinding, data: Vec<u8>) -> Result<()> {
+        // Fail now rather than in the middle of the handshake if the data can't be used
+        channel_binding.cbt_input(&data)?;
+
+        self.config.channel_binding = channel_binding;
+        self._set_cert_data(data)
+    }
+
+    // Only meaningful before the handshake starts, like the key size
+    fn _set_cbt_policy(&mut self, cbt_policy: CbtPolicy) -> Result<()> {
+        if self.state != SrdState::Initial {
+            return Err(SrdError::BadSequence);
         }
 
-        if cfg!(not(feature = "aes")) {
-            if ciphers.contains(&Cipher::AES256) {
-                return Err(SrdError::Cipher);
+        self.config.cbt_policy = Some(cbt_policy);
+        self.use_cbt = self.config.use_cbt();
+        Ok(())
+    }
+
+    // With a required CBT, a handshake can't start without certificate data
+    fn check_cbt_policy(&self) -> Result<()> {
+        if self.config.cbt_policy() == CbtPolicy::Required && !self.use_cbt {
+            return Err(SrdError::InvalidCert);
+        }
+        Ok(())
+    }
+
+    pub fn set_ciphers(&mut self, ciphers: Vec<Cipher>) -> Result<()> {
+        validate_ciphers(&ciphers)?;
+        self.config.ciphers = ciphers;
+        Ok(())
+    }
+
+    pub fn config(&self) -> &SrdConfig {
+        &self.config
+    }
+
+    // Same as authenticate(), without I/O: a client starts with no input, then every message from the peer is given
+    // until the handshake is complete. On failure, the error message to send to the peer, if any, is left in the
+    // output data.
+    pub fn step(&mut self, input_data: &[u8]) -> Result<SrdOutcome> {
+        let mut output_data = Vec::new();
+
+        let complete = match self._authenticate(input_data, &mut output_data) {
+            Ok(complete) => complete,
+            Err(e) => {
+                if !output_data.is_empty() {
+                    self.output_data = Some(output_data);
+                }
+                return Err(e);
             }
+        };
+
+        match (complete, output_data.is_empty()) {
+            (false, false) => Ok(SrdOutcome::Send(output_data)),
+            (true, false) => Ok(SrdOutcome::SendAndComplete(output_data, self.session_info())),
+            (true, true) => Ok(SrdOutcome::Complete(self.session_info())),
+            (false, true) if self.state == SrdState::AwaitingVerdict => Ok(SrdOutcome::AwaitingVerdict),
+            (false, true) => Err(SrdError::Internal("Handshake stalled".to_owned())),
+        }
+    }
+
+    fn session_info(&self) -> SessionInfo {
+        SessionInfo {
+            cipher: self.cipher,
+            key_size: self.key_size,
+            use_cbt: self.use_cbt,
+            blob: if self.config.is_server { self.blob.clone() } else { None },
+        }
+    }
+
+    // Server only: the credentials delegated by the client are checked while the delegate message is processed
+    pub fn set_credential_validator(&mut self, validator: Box<dyn CredentialValidator>) -> Result<()> {
+        if !self.config.is_server || self.state != SrdState::Initial {
+            return Err(SrdError::BadSequence);
         }
 
-        self.supported_ciphers = ciphers;
+        self.validator = Some(validator);
         Ok(())
     }
 
+    // Server with a result message and no validator: its handshake stops at AwaitingVerdict
+    pub(crate) fn needs_verdict(&self) -> bool {
+        self.config.is_server && self.config.result_message() && self.validator.is_none()
+    }
+
+    pub fn get_key_exchange(&self) -> KeyExchange {
+        self.key_exchange
+    }
+
+    // Blobs received after a challenge are looked up too
     pub fn get_blob<T: Blob>(&self) -> Result<Option<T>> {
-        if self.blob.is_some() {
-            let blob = self.blob.as_ref().unwrap();
+        for blob in self.blob.iter().chain(self.extra_blobs.iter()) {
             if blob.blob_type() == T::blob_type() {
                 let mut cursor = std::io::Cursor::new(blob.data());
                 return Ok(Some(T::read_from(&mut cursor)?));
@@ -294,39 +631,120 @@ impl Srd {
         Ok(())
     }
 
+    pub fn add_blob<T: Blob>(&mut self, blob: T) -> Result<()> {
+        let mut data = Vec::new();
+        blob.write_to(&mut data)?;
+        self.extra_blobs.push(SrdBlob::new(T::blob_type(), &data));
+        Ok(())
+    }
+
     pub fn get_raw_blob(&self) -> Option<SrdBlob> {
         return self.blob.clone();
     }
 
-    fn set_key_size(&mut self, key_size: u16) -> Result<()> {
-        match key_size {
-            256 | 512 | 1024 => {
-                self.key_size = key_size;
-                Ok(())
-            }
-            _ => Err(SrdError::InvalidKeySize),
+    // Keys for the application, like RFC 5705: independent of the SRD keys and of each other as long as the labels or
+    // contexts differ. Both sides get the same keys for the same label, context and length.
+    fn _export_keying_material(&self, label: &str, context: &[u8], len: usize) -> Result<Vec<u8>> {
+        if self.state != SrdState::Complete {
+            return Err(SrdError::BadSequence);
+        }
+
+        if len == 0 || label.is_empty() || label.len() > u16::MAX as usize || context.len() > u16::MAX as usize {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        // Lengths are included so different (label, context) pairs can't give the same info
+        let mut info = b"SRD exporter".to_vec();
+        info.write_u16::<LittleEndian>(label.len() as u16)?;
+        info.extend_from_slice(label.as_bytes());
+        info.write_u16::<LittleEndian>(context.len() as u16)?;
+        info.extend_from_slice(context);
+        info.write_u16::<LittleEndian>(len as u16)?;
+
+        let mut keying_material = vec![0u8; len];
+        self.derive_secret(&info, &mut keying_material)
+            .map_err(|_| SrdError::InvalidDataLength)?;
+        Ok(keying_material)
+    }
+
+    // Channel protecting what is exchanged after the handshake. Its keys are only derived from the handshake secret
+    // and the whole transcript, not from the delegation or integrity keys, and use the AEAD version of the cipher.
+    // It can only be taken once: a second channel would seal its records with the same nonces.
+    pub fn secure_channel(&mut self) -> Result<SecureChannel> {
+        if self.state != SrdState::Complete || self.channel_taken {
+            return Err(SrdError::BadSequence);
+        }
+
+        let mut client_key = [0u8; 32];
+        let mut client_iv = [0u8; 32];
+        let mut server_key = [0u8; 32];
+        let mut server_iv = [0u8; 32];
+        self.derive_secret(b"SRD channel client key", &mut client_key)?;
+        self.derive_secret(b"SRD channel client iv", &mut client_iv)?;
+        self.derive_secret(b"SRD channel server key", &mut server_key)?;
+        self.derive_secret(b"SRD channel server iv", &mut server_iv)?;
+
+        let cipher = self.cipher.aead();
+        self.channel_taken = true;
+        if self.config.is_server {
+            Ok(SecureChannel::new(cipher, server_key, server_iv, client_key, client_iv))
+        } else {
+            Ok(SecureChannel::new(cipher, client_key, client_iv, server_key, server_iv))
         }
     }
 
+    // Key size in bytes: 256 (2048-bit), 512 (4096-bit) or 1024 (8192-bit). Only meaningful before the handshake
+    // starts: the client proposes it and the server only accepts it if it isn't below its minimum.
+    fn _set_key_size(&mut self, key_size: u16) -> Result<()> {
+        if self.state != SrdState::Initial {
+            return Err(SrdError::BadSequence);
+        }
+
+        validate_key_size(key_size, self.config.min_key_size)?;
+        self.config.key_size = key_size;
+        self.key_size = key_size;
+        Ok(())
+    }
+
+    fn negotiate_key_size(&mut self, key_size: u16) -> Result<()> {
+        validate_key_size(key_size, self.config.min_key_size)?;
+        self.key_size = key_size;
+        Ok(())
+    }
+
+    fn negotiate_version(&mut self, version: u16) -> Result<()> {
+        if version < self.config.min_version {
+            return Err(SrdError::UnsupportedVersion);
+        }
+
+        self.version = version;
+        Ok(())
+    }
+
     fn read_msg(&mut self, buffer: &[u8]) -> Result<SrdMessage> {
         let mut reader = std::io::Cursor::new(buffer);
         let msg = SrdMessage::read_from(&mut reader)?;
 
+        // Not authenticated: the peer's error is reported as such, never as one of ours
+        if let SrdMessage::Error(_, ref error) = msg {
+            return Err(SrdError::PeerError(error.code(), error.reason().to_owned()));
+        }
+
         if msg.seq_num() != self.seq_num {
             return Err(SrdError::BadSequence);
         }
         self.seq_num += 1;
 
-        if msg.has_skip() && !self.skip_delegation {
+        if msg.has_skip() && !self.config.skip_delegation {
             return Err(SrdError::Proto(String::from("SRD_FLAG_SKIP not expected")));
         }
 
-        if !msg.has_skip() && self.skip_delegation {
+        if !msg.has_skip() && self.config.skip_delegation {
             return Err(SrdError::Proto(String::from("SRD_FLAG_SKIP expected")));
         }
 
         // Keep the message to calculate future mac value
-        self.messages.push(Vec::from(buffer));
+        self.transcript.add_message(buffer, msg.has_mac())?;
 
         // Verify mac value right now. We can't validate mac value for accept msg since we need information from
         // the message to generate the integrety key. So only for this message type, it is verified later.
@@ -334,8 +752,8 @@ impl Srd {
             self.validate_mac(&msg)?;
         }
 
-        // If CBT flag is set, we have to use CBT
-        if msg.has_cbt() && !self.use_cbt {
+        // If CBT flag is set, we have to use CBT. The server answers an initiate message according to its policy.
+        if msg.has_cbt() && !self.use_cbt && msg.msg_type() != srd_msg_id::SRD_INITIATE_MSG_ID {
             return Err(SrdError::InvalidCert);
         }
 
@@ -351,25 +769,22 @@ impl Srd {
             return Err(SrdError::BadSequence);
         }
 
-        if self.skip_delegation {
+        if self.config.skip_delegation {
             msg.set_skip();
         }
 
-        // Keep the message to calculate future mac value. The message doesn't contain the MAC since it is not calculated yet
-        // It is not a problem since MAC are not included in MAC calculation
-        let mut v = Vec::new();
-        msg.write_to(&mut v)?;
-        self.messages.push(v);
-
+        // The MAC covers the message itself, without the MAC field that isn't calculated yet
         if msg.has_mac() {
-            msg.set_mac(&self.compute_mac()?)
-                .expect("Should never happen, has_mac returned true");
+            let mut v = Vec::new();
+            msg.write_to(&mut v)?;
+            let mac = self.transcript.mac(&v[..v.len() - 32])?;
+            msg.set_mac(&mac).expect("Should never happen, has_mac returned true");
         }
 
-        // Remove the last message to insert it again with the mac value (not really needed, just to keep exactly what it is sent.
-        self.messages.pop();
+        // Keep the message to calculate future mac value
+        let start = buffer.len();
         msg.write_to(buffer)?;
-        self.messages.push(buffer.clone());
+        self.transcript.add_message(&buffer[start..], msg.has_mac())?;
 
         self.seq_num += 1;
 
@@ -383,8 +798,8 @@ impl Srd {
             let mut hmac = Hmac::<Sha256>::new_from_slice(&self.integrity_key)?;
 
             hmac.update(nonce);
-            if let Some(ref cert_data) = self.cert_data {
-                hmac.update(&cert_data);
+            if let Some(ref cert_data) = self.config.cert_data {
+                hmac.update(&self.config.channel_binding.cbt_input(cert_data)?);
             } else {
                 return Err(SrdError::InvalidCert);
             }
@@ -393,27 +808,10 @@ impl Srd {
         Ok(cbt_data)
     }
 
-    fn compute_mac(&self) -> Result<Vec<u8>> {
-        let mut hmac = Hmac::<Sha256>::new_from_slice(&self.integrity_key)?;
-        hmac.update(
-            &self
-                .get_mac_data()
-                .map_err(|_| SrdError::Internal("MAC can't be calculated".to_owned()))?,
-        );
-        Ok(hmac.finalize().into_bytes().to_vec())
-    }
-
     fn validate_mac(&self, msg: &SrdMessage) -> Result<()> {
         if msg.has_mac() {
-            let mut hmac = Hmac::<Sha256>::new_from_slice(&self.integrity_key)?;
-            hmac.update(
-                &self
-                    .get_mac_data()
-                    .map_err(|_| SrdError::Internal("MAC can't be calculated".to_owned()))?,
-            );
-
             if let Some(mac) = msg.mac() {
-                hmac.verify(mac).map_err(|_| SrdError::InvalidMac)
+                self.transcript.verify_mac(mac)
             } else {
                 Err(SrdError::Internal(
                     "Msg should have a MAC but we can't get it".to_owned(),
@@ -425,29 +823,12 @@ impl Srd {
         }
     }
 
-    // Send back all the data that has to be used to calculate the MAC. We used all messages, without all MAC fields
-    fn get_mac_data(&self) -> Result<Vec<u8>> {
-        let mut result = Vec::new();
-
-        for message in &self.messages {
-            let hdr = SrdHeader::read_from(&mut message.as_slice())?;
-            if hdr.has_mac() {
-                // Keep the message without the MAC at the end (32 bytes)
-                let slice = message.as_slice();
-                let last_index = slice.len() - 32;
-                result.write(&slice[0..last_index])?;
-            } else {
-                result.write(message.as_slice())?;
-            }
-        }
-
-        Ok(result)
-    }
-
     // Client initiate
     fn client_authenticate_0(&mut self, mut output_data: &mut Vec<u8>) -> Result<()> {
+        self.check_cbt_policy()?;
+
         let mut cipher_flags = 0u32;
-        for c in &self.supported_ciphers {
+        for c in &self.config.ciphers {
             cipher_flags |= c.flag();
         }
 
@@ -455,36 +836,80 @@ impl Srd {
             return Err(SrdError::Cipher);
         }
 
-        // Negotiate
-        let mut out_msg = new_srd_initiate_msg(self.seq_num, self.use_cbt, cipher_flags, self.key_size)?;
+        // Negotiate. The key size is still sent with X25519, in case the server only supports Diffie-Hellman.
+        let mut out_msg = new_srd_initiate_msg(
+            self.seq_num,
+            self.use_cbt,
+            cipher_flags,
+            self.key_size,
+            self.config.x25519,
+        )?;
+        if self.version > SRD_VERSION_1 {
+            out_msg.set_version(self.version);
+        }
+        if !self.config.pinned_keys.is_empty() {
+            out_msg.add_signature_flag();
+        }
+        if self.config.pake() {
+            out_msg.add_pake_flag();
+        }
+        if self.use_result {
+            out_msg.add_result_flag();
+        }
         self.write_msg(&mut out_msg, &mut output_data)?;
         Ok(())
     }
 
     // Server initiate -> offer
     fn server_authenticate_0(&mut self, input_data: &[u8], mut output_data: &mut Vec<u8>) -> Result<()> {
+        self.check_cbt_policy()?;
+
         let input_msg = self.read_msg(input_data)?;
 
         match input_msg {
             SrdMessage::Initiate(hdr, initiate) => {
-                self.use_cbt = hdr.has_cbt();
-
-                // Negotiate
-                self.set_key_size(initiate.key_size())?;
-                self.find_dh_parameters()?;
+                // CBT is used only if both sides want it, and can't be dropped if our policy requires it
+                if !hdr.has_cbt() && self.config.cbt_policy() == CbtPolicy::Required {
+                    return Err(SrdError::InvalidCbt);
+                }
+                self.use_cbt = self.use_cbt && hdr.has_cbt();
+                self.use_result = self.use_result && hdr.has_result();
+                // A client that doesn't wait for a result can't answer challenges
+                if !self.config.challenges.is_empty() && !self.use_result {
+                    return Err(SrdError::Proto("Challenges need the result message".to_owned()));
+                }
 
-                let key_size = initiate.key_size();
+                // Negotiate. A client without a version speaks version 1.
+                let client_version = if hdr.has_version() {
+                    initiate.version()
+                } else {
+                    SRD_VERSION_1
+                };
+                self.negotiate_version(client_version.min(self.config.max_version))?;
+                // Both sides have to agree on PAKE, it can't be dropped
+                if hdr.has_pake() != self.config.pake() {
+                    return Err(SrdError::InvalidCredentials);
+                }
 
-                let mut private_key_bytes = vec![0u8; self.key_size as usize];
-                OsRng.try_fill_bytes(&mut private_key_bytes)?;
+                self.negotiate_key_size(initiate.key_size())?;
+                if hdr.has_x25519() && self.config.x25519 {
+                    self.key_exchange = KeyExchange::X25519;
+                    self.key_size = X25519_KEY_SIZE;
+                } else {
+                    self.find_dh_parameters()?;
+                }
 
-                // Challenge
-                self.private_key = BigUint::from_bytes_be(&private_key_bytes);
-                let public_key = self.generator.modpow(&self.private_key, &self.prime);
+                // Challenge. With PAKE, the offer still contains the generator of the group, the key pair uses the one
+                // derived from the secret.
                 OsRng.try_fill_bytes(&mut self.server_nonce)?;
+                let generator = self.generator.to_bytes_be();
+                if self.config.pake() {
+                    self.generator = self.pake_generator()?;
+                }
+                let public_key = self.generate_key_pair()?;
 
                 let mut cipher_flags = 0u32;
-                for c in &self.supported_ciphers {
+                for c in &self.config.ciphers {
                     cipher_flags |= c.flag();
                 }
 
@@ -492,16 +917,48 @@ impl Srd {
                     return Err(SrdError::Cipher);
                 }
 
-                let mut out_msg = new_srd_offer_msg(
-                    self.seq_num,
-                    self.use_cbt,
-                    cipher_flags,
-                    key_size,
-                    self.generator.to_bytes_be(),
-                    self.prime.to_bytes_be(),
-                    public_key.to_bytes_be(),
-                    self.server_nonce,
-                );
+                let mut out_msg = match self.key_exchange {
+                    KeyExchange::Dh => new_srd_offer_msg(
+                        self.seq_num,
+                        self.use_cbt,
+                        cipher_flags,
+                        self.key_size,
+                        generator,
+                        self.prime.to_bytes_be(),
+                        public_key,
+                        self.server_nonce,
+                    ),
+                    KeyExchange::X25519 => {
+                        let mut x25519_public_key = [0u8; 32];
+                        x25519_public_key.copy_from_slice(&public_key);
+                        new_srd_x25519_offer_msg(
+                            self.seq_num,
+                            self.use_cbt,
+                            cipher_flags,
+                            x25519_public_key,
+                            self.server_nonce,
+                        )
+                    }
+                };
+
+                // A version 1 client wouldn't expect a version in the offer
+                if self.version > SRD_VERSION_1 {
+                    out_msg.set_version(self.version);
+                }
+
+                if self.config.pake() {
+                    out_msg.add_pake_flag();
+                }
+                if self.use_result {
+                    out_msg.add_result_flag();
+                }
+
+                // Same for the signature, only added when asked for
+                if hdr.has_signature() {
+                    if let Some(ref signing_key) = self.config.signing_key {
+                        self.sign_offer(&mut out_msg, signing_key)?;
+                    }
+                }
 
                 self.write_msg(&mut out_msg, &mut output_data)?;
 
@@ -518,35 +975,64 @@ impl Srd {
         //Challenge
         let input_msg = self.read_msg(input_data)?;
         match input_msg {
-            SrdMessage::Offer(_hdr, offer) => {
-                // Verify server key_size
-                if offer.key_size() != self.key_size {
-                    return Err(SrdError::Proto(
-                        "Key size received in offer message is not equal to key size sent to server".to_owned(),
-                    ));
+            SrdMessage::Offer(hdr, offer) => {
+                // Nothing in the offer can be trusted before this
+                if !self.config.pinned_keys.is_empty() {
+                    self.verify_offer_signature(&offer, input_data)?;
                 }
 
-                let server_ciphers = Cipher::from_flags(offer.ciphers);
+                if self.use_cbt && !hdr.has_cbt() {
+                    if self.config.cbt_policy() == CbtPolicy::Required {
+                        return Err(SrdError::InvalidCbt);
+                    }
+                    self.use_cbt = false;
+                }
 
-                self.generator = BigUint::from_bytes_be(&offer.generator);
-                self.prime = BigUint::from_bytes_be(&offer.prime);
+                // The server can only pick a version we proposed. A downgrade to an older version we still accept is
+                // detected later: the MAC covers the initiate message as we sent it.
+                if offer.version() > self.version {
+                    return Err(SrdError::UnsupportedVersion);
+                }
+                self.negotiate_version(offer.version())?;
+                self.use_result = self.use_result && hdr.has_result();
 
-                let mut private_key_bytes = vec![0u8; self.key_size as usize];
+                if hdr.has_x25519() {
+                    if !self.config.x25519 {
+                        return Err(SrdError::Proto("X25519 was not proposed to the server".to_owned()));
+                    }
 
-                OsRng.try_fill_bytes(&mut private_key_bytes)?;
+                    self.key_exchange = KeyExchange::X25519;
+                    self.key_size = X25519_KEY_SIZE;
+                } else {
+                    // Verify server key_size
+                    if offer.key_size() != self.key_size {
+                        return Err(SrdError::Proto(
+                            "Key size received in offer message is not equal to key size sent to server".to_owned(),
+                        ));
+                    }
 
-                self.private_key = BigUint::from_bytes_be(&private_key_bytes);
+                    self.generator = BigUint::from_bytes_be(&offer.generator);
+                    self.prime = BigUint::from_bytes_be(&offer.prime);
+                    self.validate_dh_parameters()?;
+                }
 
-                let public_key = self.generator.modpow(&self.private_key, &self.prime);
+                self.server_nonce = offer.nonce;
+                if hdr.has_pake() != self.config.pake() {
+                    return Err(SrdError::InvalidCredentials);
+                }
+                if self.config.pake() {
+                    self.generator = self.pake_generator()?;
+                }
+
+                let server_ciphers = Cipher::from_flags(offer.ciphers);
+
+                let public_key = self.generate_key_pair()?;
 
                 OsRng.try_fill_bytes(&mut self.client_nonce)?;
 
-                self.server_nonce = offer.nonce;
-                self.secret_key = BigUint::from_bytes_be(&offer.public_key)
-                    .modpow(&self.private_key, &self.prime)
-                    .to_bytes_be();
+                self.compute_secret_key(&offer.public_key)?;
 
-                self.derive_keys();
+                self.derive_keys()?;
 
                 let key_size = offer.key_size();
 
@@ -556,7 +1042,7 @@ impl Srd {
                 // Accept
                 let mut common_ciphers = Vec::new();
                 for c in &server_ciphers {
-                    if self.supported_ciphers.contains(c) {
+                    if self.config.ciphers.contains(c) {
                         common_ciphers.push(*c);
                     }
                 }
@@ -568,7 +1054,7 @@ impl Srd {
                     self.use_cbt,
                     self.cipher.flag(),
                     key_size,
-                    public_key.to_bytes_be(),
+                    public_key,
                     self.client_nonce,
                     cbt_data,
                 );
@@ -597,20 +1083,22 @@ impl Srd {
 
                 self.cipher = *chosen_cipher.get(0).unwrap_or(&Cipher::XChaCha20);
 
-                if !self.supported_ciphers.contains(&self.cipher) {
+                if !self.config.ciphers.contains(&self.cipher) {
                     return Err(SrdError::Cipher);
                 }
 
                 self.client_nonce = accept.nonce;
 
-                self.secret_key = BigUint::from_bytes_be(&accept.public_key)
-                    .modpow(&self.private_key, &self.prime)
-                    .to_bytes_be();
+                self.compute_secret_key(&accept.public_key)?;
 
-                self.derive_keys();
+                self.derive_keys()?;
 
-                // Integrety_key has been generated. We has to verify the mac here.
-                self.validate_mac(&message)?;
+                // Integrety_key has been generated. We has to verify the mac here. With PAKE, a wrong MAC most likely
+                // means the client doesn't have the same secret.
+                match self.validate_mac(&message) {
+                    Err(SrdError::InvalidMac) if self.config.pake() => return Err(SrdError::InvalidCredentials),
+                    result => result?,
+                }
 
                 // Verify client cbt
                 let cbt_data = self.compute_cbt(&self.client_nonce)?;
@@ -618,6 +1106,11 @@ impl Srd {
                     return Err(SrdError::InvalidCbt);
                 }
 
+                // Only authenticated nonces are recorded, so forged messages can't fill the cache
+                if let Some(ref nonce_cache) = self.config.nonce_cache {
+                    nonce_cache.insert(&self.client_nonce)?;
+                }
+
                 // Confirm
                 // Generate server cbt
                 let cbt_data = self.compute_cbt(&self.server_nonce)?;
@@ -671,9 +1164,93 @@ impl Srd {
         }
     }
 
-    // Server delegate -> result
-    fn server_authenticate_2(&mut self, input_data: &[u8]) -> Result<()> {
-        if self.skip_delegation {
+    // Client result, or challenge -> delegate. Returns true if a challenge was answered.
+    fn client_authenticate_3(&mut self, input_data: &[u8], output_data: &mut Vec<u8>) -> Result<bool> {
+        let input_msg = self.read_msg(input_data)?;
+        match input_msg {
+            SrdMessage::Result(_hdr, result) => {
+                if result.status != SRD_RESULT_SUCCESS {
+                    return Err(SrdError::from_code(result.status, "Credentials rejected"));
+                }
+
+                Ok(false)
+            }
+            SrdMessage::Challenge(_hdr, challenge) => {
+                if self.challenge_count >= MAX_CHALLENGES {
+                    return Err(SrdError::Proto("Too many challenges".to_owned()));
+                }
+                self.challenge_count += 1;
+
+                let mut out_msg = match self.extra_blobs.iter().find(|b| b.blob_type() == challenge.blob_type) {
+                    None => {
+                        return Err(SrdError::MissingBlob);
+                    }
+                    Some(b) => new_srd_delegate_msg(
+                        self.seq_num,
+                        self.use_cbt,
+                        b,
+                        self.cipher,
+                        &self.delegation_key,
+                        &self.delegate_iv(),
+                    )?,
+                };
+
+                self.write_msg(&mut out_msg, output_data)?;
+                Ok(true)
+            }
+            _ => Err(SrdError::BadSequence),
+        }
+    }
+
+    // Each delegate message has its own IV, so the delegation key never encrypts two blobs with the same one
+    fn delegate_iv(&self) -> [u8; 32] {
+        if self.challenge_count == 0 {
+            return self.iv;
+        }
+
+        let mut hash = Sha256::new();
+        hash.update(b"SRD challenge iv");
+        hash.update(self.iv);
+        hash.update((self.challenge_count as u32).to_le_bytes());
+
+        let mut iv = [0u8; 32];
+        iv.copy_from_slice(&hash.finalize());
+        iv
+    }
+
+    // Server verdict -> result. Rejected credentials fail the handshake, but the result still has to be sent.
+    fn _send_result(&mut self, accepted: bool, output_data: &mut Vec<u8>) -> Result<()> {
+        if !self.config.is_server || self.state != SrdState::AwaitingVerdict {
+            return Err(SrdError::BadSequence);
+        }
+
+        match self.write_result(accepted, output_data) {
+            Ok(()) if accepted => self.transition(SrdState::Complete),
+            Ok(()) => {
+                self.state = SrdState::Failed;
+                Ok(())
+            }
+            Err(e) => {
+                self.state = SrdState::Failed;
+                Err(e)
+            }
+        }
+    }
+
+    fn write_result(&mut self, accepted: bool, output_data: &mut Vec<u8>) -> Result<()> {
+        let status = if accepted {
+            SRD_RESULT_SUCCESS
+        } else {
+            SrdError::InvalidCredentials.code()
+        };
+        let mut out_msg = new_srd_result_msg(self.seq_num, self.use_cbt, status);
+
+        self.write_msg(&mut out_msg, output_data)
+    }
+
+    // Server delegate -> challenge or result. Returns true if a challenge was sent.
+    fn server_authenticate_2(&mut self, input_data: &[u8], output_data: &mut Vec<u8>) -> Result<bool> {
+        if self.config.skip_delegation {
             return Err(SrdError::BadSequence);
         }
 
@@ -681,14 +1258,155 @@ impl Srd {
         let input_msg = self.read_msg(input_data)?;
         match input_msg {
             SrdMessage::Delegate(_hdr, delegate) => {
-                self.blob = Some(delegate.get_data(self.cipher, &self.delegation_key, &self.iv)?);
+                let blob = delegate.get_data(self.cipher, &self.delegation_key, &self.delegate_iv())?;
 
-                Ok(())
+                // A challenge has to be answered with the blob asked for
+                if self.challenge_count > 0 && blob.blob_type() != self.config.challenges[self.challenge_count - 1] {
+                    return Err(SrdError::MissingBlob);
+                }
+
+                // Without a validator, the verdict is given later with send_result(), if there is a result message
+                let verdict = self.validator.as_mut().map(|validator| validator.validate(&blob));
+                if self.challenge_count == 0 {
+                    self.blob = Some(blob);
+                } else {
+                    self.extra_blobs.push(blob);
+                }
+
+                if verdict == Some(false) {
+                    if self.use_result {
+                        self.write_result(false, output_data)?;
+                    }
+                    return Err(SrdError::InvalidCredentials);
+                }
+
+                if self.challenge_count < self.config.challenges.len() {
+                    let mut out_msg =
+                        new_srd_challenge_msg(self.seq_num, self.use_cbt, &self.config.challenges[self.challenge_count])?;
+                    self.challenge_count += 1;
+
+                    self.write_msg(&mut out_msg, output_data)?;
+                    return Ok(true);
+                }
+
+                if verdict == Some(true) && self.use_result {
+                    self.write_result(true, output_data)?;
+                }
+
+                Ok(false)
             }
             _ => return Err(SrdError::BadSequence),
         }
     }
 
+    // PAKE generator, CPace-like: the secret and the session (initiate message, server nonce, group) are hashed to an
+    // element of the group, and squared so the generator is in the subgroup of order q. Without the secret, a
+    // man-in-the-middle can't compute the shared secret of either side.
+    fn pake_generator(&self) -> Result<BigUint> {
+        let secret = self.config.pake_secret.as_ref().ok_or(SrdError::InvalidCredentials)?;
+
+        let prime = self.prime.to_bytes_be();
+        let hkdf = Hkdf::<Sha256>::new(Some(&self.server_nonce), secret);
+
+        // 128 more bits than the prime so the reduction is uniform enough
+        let mut element = vec![0u8; prime.len() + 16];
+        hkdf.expand_multi_info(&[b"SRD PAKE generator", self.transcript.initiate(), &prime], &mut element)
+            .map_err(|_| SrdError::Internal("HKDF expansion failed".to_owned()))?;
+
+        let generator = (BigUint::from_bytes_be(&element) % &self.prime).modpow(&BigUint::from(2u8), &self.prime);
+        if generator < BigUint::from(2u8) {
+            return Err(SrdError::Crypto);
+        }
+        Ok(generator)
+    }
+
+    // The offer signature covers the initiate message and the offer itself, up to the signature
+    fn offer_signed_data(&self, offer: &[u8]) -> Vec<u8> {
+        let mut data = OFFER_SIGNATURE_CONTEXT.to_vec();
+        data.extend_from_slice(self.transcript.initiate());
+        data.extend_from_slice(offer);
+        data
+    }
+
+    fn sign_offer(&self, msg: &mut SrdMessage, signing_key: &ServerSigningKey) -> Result<()> {
+        msg.add_signature_flag();
+
+        let mut offer = Vec::new();
+        msg.write_to(&mut offer)?;
+        let signature = signing_key.sign(&self.offer_signed_data(&offer))?;
+
+        if let SrdMessage::Offer(_, ref mut offer) = *msg {
+            offer.set_signature(signing_key.algorithm().id(), signature);
+        }
+        Ok(())
+    }
+
+    fn verify_offer_signature(&self, offer: &SrdOffer, input_data: &[u8]) -> Result<()> {
+        let (algorithm, signature) = offer.signature().ok_or(SrdError::UntrustedServer)?;
+        let algorithm = SignatureAlgorithm::from_id(algorithm).ok_or(SrdError::UntrustedServer)?;
+
+        let signed_offer = &input_data[..input_data.len() - offer.signature_size()];
+        let data = self.offer_signed_data(signed_offer);
+
+        let trusted = self
+            .config
+            .pinned_keys
+            .iter()
+            .filter(|key| key.algorithm() == algorithm)
+            .any(|key| key.verify(&data, signature).is_ok());
+
+        if trusted {
+            Ok(())
+        } else {
+            Err(SrdError::UntrustedServer)
+        }
+    }
+
+    // Generates our ephemeral key pair for the negotiated key exchange and returns the public key
+    fn generate_key_pair(&mut self) -> Result<Vec<u8>> {
+        match self.key_exchange {
+            KeyExchange::Dh => {
+                let mut private_key_bytes = vec![0u8; self.key_size as usize];
+                OsRng.try_fill_bytes(&mut private_key_bytes)?;
+
+                self.private_key = BigUint::from_bytes_be(&private_key_bytes);
+                Ok(self.generator.modpow(&self.private_key, &self.prime).to_bytes_be())
+            }
+            KeyExchange::X25519 => {
+                OsRng.try_fill_bytes(&mut self.x25519_private_key)?;
+                Ok(x25519(self.x25519_private_key, X25519_BASEPOINT_BYTES).to_vec())
+            }
+        }
+    }
+
+    fn compute_secret_key(&mut self, peer_public_key: &[u8]) -> Result<()> {
+        match self.key_exchange {
+            KeyExchange::Dh => {
+                let peer_public_key = BigUint::from_bytes_be(peer_public_key);
+                validate_public_key(&peer_public_key, &self.prime)?;
+
+                self.secret_key = peer_public_key.modpow(&self.private_key, &self.prime).to_bytes_be();
+            }
+            KeyExchange::X25519 => {
+                if peer_public_key.len() != X25519_KEY_SIZE as usize {
+                    return Err(SrdError::InvalidPublicKey);
+                }
+
+                let mut u = [0u8; 32];
+                u.copy_from_slice(peer_public_key);
+                let secret_key = x25519(self.x25519_private_key, u);
+
+                // A low order point gives an all-zero secret
+                if secret_key.iter().all(|&b| b == 0) {
+                    return Err(SrdError::InvalidPublicKey);
+                }
+
+                self.secret_key = secret_key.to_vec();
+            }
+        }
+        Ok(())
+    }
+
     fn find_dh_parameters(&mut self) -> Result<()> {
         match self.key_size {
             256 => {
@@ -710,7 +1428,41 @@ impl Srd {
         }
     }
 
-    fn derive_keys(&mut self) {
+    // A client only accepts the well-known groups, or the ones it has been explicitly configured with, so a server
+    // can't impose a weak or composite modulus.
+    fn validate_dh_parameters(&self) -> Result<()> {
+        if self.prime.bits().div_ceil(8) != self.key_size as u64 {
+            return Err(SrdError::InvalidDhParams);
+        }
+
+        let is_known_group = SRD_DH_PARAMS.iter().any(|params| {
+            BigUint::from_bytes_be(params.g_data) == self.generator && BigUint::from_bytes_be(params.p_data) == self.prime
+        });
+
+        let is_allowed_group = self.config.dh_groups.iter().any(|group| {
+            BigUint::from_bytes_be(&group.generator) == self.generator && BigUint::from_bytes_be(&group.prime) == self.prime
+        });
+
+        if is_known_group || is_allowed_group {
+            Ok(())
+        } else {
+            Err(SrdError::InvalidDhParams)
+        }
+    }
+
+    fn derive_keys(&mut self) -> Result<()> {
+        if self.version >= SRD_VERSION_2 {
+            self.derive_keys_hkdf()?;
+        } else {
+            self.derive_keys_sha256()?;
+        }
+
+        // Messages can be authenticated from now on
+        self.transcript.set_mac_key(&self.integrity_key)
+    }
+
+    // Version 1 key schedule
+    fn derive_keys_sha256(&mut self) -> Result<()> {
         let mut hash = Sha256::new();
         hash.update(&self.client_nonce);
         hash.update(&self.secret_key);
@@ -730,7 +1482,62 @@ impl Srd {
         hash.update(&self.server_nonce);
 
         self.iv.clone_from_slice(&hash.finalize().to_vec());
+        Ok(())
     }
+
+    // Version 2 key schedule: HKDF-SHA256 with both nonces as salt, and each key expanded with its own label and the
+    // hash of the initiate and offer messages, so the keys are bound to what has been negotiated.
+    fn derive_keys_hkdf(&mut self) -> Result<()> {
+        let mut salt = Vec::new();
+        salt.extend_from_slice(&self.client_nonce);
+        salt.extend_from_slice(&self.server_nonce);
+
+        let transcript_hash = *self.transcript.negotiation_hash();
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &self.secret_key);
+
+        let expand = |label: &[u8], key: &mut [u8; 32]| {
+            hkdf.expand_multi_info(&[label, &transcript_hash], key)
+                .map_err(|_| SrdError::Internal("HKDF expansion failed".to_owned()))
+        };
+
+        expand(b"SRD v2 delegation key", &mut self.delegation_key)?;
+        expand(b"SRD v2 integrity key", &mut self.integrity_key)?;
+        expand(b"SRD v2 iv", &mut self.iv)
+    }
+
+    // Secret for use after the handshake, whatever the version: same HKDF as derive_keys_hkdf(), but bound to every
+    // message of the handshake.
+    fn derive_secret(&self, label: &[u8], secret: &mut [u8]) -> Result<()> {
+        let mut salt = Vec::new();
+        salt.extend_from_slice(&self.client_nonce);
+        salt.extend_from_slice(&self.server_nonce);
+
+        Hkdf::<Sha256>::new(Some(&salt), &self.secret_key)
+            .expand_multi_info(&[label, &self.transcript.hash()], secret)
+            .map_err(|_| SrdError::Internal("HKDF expansion failed".to_owned()))
+    }
+}
+
+fn is_final_msg(buffer: &[u8]) -> bool {
+    match SrdHeader::read_from(&mut std::io::Cursor::new(buffer)) {
+        Ok(hdr) => hdr.msg_type() == srd_msg_id::SRD_ERROR_MSG_ID || hdr.msg_type() == srd_msg_id::SRD_RESULT_MSG_ID,
+        Err(_) => false,
+    }
+}
+
+// The peer public key has to be in [2, p-2]. 0 and 1 would force a known secret, p-1 would confine it to {1, p-1}.
+// Every group in use has a safe prime p = 2q + 1: the SRD_DH_PARAMS primes are tested, and groups allowed with
+// SrdBuilder::allow_dh_group() are checked when the config is built. {1} and {1, p-1} are then the only small
+// subgroups, so the range check is the subgroup check: any other key has order q or 2q.
+fn validate_public_key(public_key: &BigUint, prime: &BigUint) -> Result<()> {
+    let two = BigUint::from(2u8);
+
+    if *public_key < two || *prime < two || *public_key > prime - &two {
+        return Err(SrdError::InvalidPublicKey);
+    }
+
+    Ok(())
 }
 
 #[cfg(feature = "wasm")]
@@ -0,0 +1,443 @@
+use channel_binding::ChannelBinding;
+use cipher::Cipher;
+use dh_params::{is_safe_prime, DhGroup};
+use messages::{SRD_LATEST_VERSION, SRD_VERSION_1};
+use nonce_cache::NonceCache;
+use num_bigint::BigUint;
+use server_key::{ServerPublicKey, ServerSigningKey};
+use srd::Srd;
+use srd_errors::SrdError;
+use std::sync::Arc;
+use Result;
+
+cfg_if! {
+    if #[cfg(feature = "wasm")] {
+        use wasm_bindgen::prelude::*;
+    }
+}
+
+// Whether the channel binding token (CBT) is checked. Without an explicit policy, it is required as soon as
+// certificate data is set, so it can't be dropped by the peer or by someone stripping SRD_FLAG_CBT.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CbtPolicy {
+    // The handshake fails if the peer doesn't use CBT
+    Required,
+    // CBT is used if both sides have certificate data
+    Optional,
+    // CBT is never used
+    Disabled,
+}
+
+// A config can only be obtained through SrdBuilder, so it is always valid. The same config can be used to create as
+// many contexts as needed.
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Clone)]
+pub struct SrdConfig {
+    pub(crate) is_server: bool,
+    pub(crate) skip_delegation: bool,
+    pub(crate) key_size: u16,
+    pub(crate) min_key_size: u16,
+    pub(crate) ciphers: Vec<Cipher>,
+    pub(crate) cert_data: Option<Vec<u8>>,
+    pub(crate) channel_binding: ChannelBinding,
+    pub(crate) cbt_policy: Option<CbtPolicy>,
+    pub(crate) dh_groups: Vec<DhGroup>,
+    pub(crate) x25519: bool,
+    pub(crate) min_version: u16,
+    pub(crate) max_version: u16,
+    // Secrets aren't serialized with the context: they are only needed before the offer is sent or received
+    #[cfg_attr(feature = "ser", serde(skip))]
+    pub(crate) signing_key: Option<ServerSigningKey>,
+    pub(crate) pinned_keys: Vec<ServerPublicKey>,
+    #[cfg_attr(feature = "ser", serde(skip))]
+    pub(crate) pake_secret: Option<Vec<u8>>,
+    pub(crate) pake: bool,
+    pub(crate) result_message: bool,
+    pub(crate) challenges: Vec<String>,
+    #[cfg_attr(feature = "ser", serde(skip))]
+    pub(crate) nonce_cache: Option<Arc<dyn NonceCache>>,
+}
+
+impl SrdConfig {
+    pub(crate) fn new(is_server: bool) -> SrdConfig {
+        let ciphers;
+        if cfg!(feature = "fips") {
+            ciphers = vec![Cipher::AES256GCM, Cipher::AES256];
+        } else if cfg!(feature = "aes") {
+            ciphers = vec![
+                Cipher::XChaCha20Poly1305,
+                Cipher::ChaCha20Poly1305,
+                Cipher::AES256GCM,
+                Cipher::XChaCha20,
+                Cipher::ChaCha20,
+                Cipher::AES256,
+            ];
+        } else {
+            ciphers = vec![
+                Cipher::XChaCha20Poly1305,
+                Cipher::ChaCha20Poly1305,
+                Cipher::XChaCha20,
+                Cipher::ChaCha20,
+            ];
+        }
+
+        SrdConfig {
+            is_server,
+            skip_delegation: false,
+            key_size: 256,
+            min_key_size: 256,
+            ciphers,
+            cert_data: None,
+            channel_binding: ChannelBinding::RawCert,
+            cbt_policy: None,
+            dh_groups: Vec::new(),
+            x25519: false,
+            min_version: SRD_VERSION_1,
+            max_version: SRD_LATEST_VERSION,
+            signing_key: None,
+            pinned_keys: Vec::new(),
+            pake_secret: None,
+            pake: false,
+            result_message: false,
+            challenges: Vec::new(),
+            nonce_cache: None,
+        }
+    }
+
+    pub fn is_server(&self) -> bool {
+        self.is_server
+    }
+
+    pub fn skip_delegation(&self) -> bool {
+        self.skip_delegation
+    }
+
+    pub fn key_size(&self) -> u16 {
+        self.key_size
+    }
+
+    pub fn min_key_size(&self) -> u16 {
+        self.min_key_size
+    }
+
+    pub fn x25519(&self) -> bool {
+        self.x25519
+    }
+
+    pub fn min_version(&self) -> u16 {
+        self.min_version
+    }
+
+    pub fn max_version(&self) -> u16 {
+        self.max_version
+    }
+
+    pub fn pinned_keys(&self) -> &[ServerPublicKey] {
+        &self.pinned_keys
+    }
+
+    pub fn result_message(&self) -> bool {
+        self.result_message
+    }
+
+    pub fn challenges(&self) -> &[String] {
+        &self.challenges
+    }
+
+    // Still known once the secret is dropped by serialization
+    pub fn pake(&self) -> bool {
+        self.pake
+    }
+
+    pub fn ciphers(&self) -> &[Cipher] {
+        &self.ciphers
+    }
+
+    pub fn cert_data(&self) -> Option<&[u8]> {
+        self.cert_data.as_deref()
+    }
+
+    pub fn channel_binding(&self) -> ChannelBinding {
+        self.channel_binding
+    }
+
+    pub fn cbt_policy(&self) -> CbtPolicy {
+        match self.cbt_policy {
+            Some(policy) => policy,
+            None if self.cert_data.is_some() => CbtPolicy::Required,
+            None => CbtPolicy::Optional,
+        }
+    }
+
+    // CBT is proposed by a client, or accepted by a server, only if this is true
+    pub(crate) fn use_cbt(&self) -> bool {
+        self.cert_data.is_some() && self.cbt_policy() != CbtPolicy::Disabled
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        validate_key_size(self.min_key_size, 256)?;
+
+        // The key size is chosen by the client, the server only enforces its minimum
+        if !self.is_server {
+            validate_key_size(self.key_size, self.min_key_size)?;
+        }
+
+        if self.min_version < SRD_VERSION_1 || self.min_version > self.max_version || self.max_version > SRD_LATEST_VERSION
+        {
+            return Err(SrdError::UnsupportedVersion);
+        }
+
+        for group in &self.dh_groups {
+            validate_dh_group(group)?;
+        }
+
+        if self.cbt_policy() == CbtPolicy::Required && self.cert_data.is_none() {
+            return Err(SrdError::InvalidCert);
+        }
+
+        if let Some(ref pake_secret) = self.pake_secret {
+            // The PAKE generator only exists in the Diffie-Hellman groups
+            if pake_secret.is_empty() || self.x25519 {
+                return Err(SrdError::InvalidCredentials);
+            }
+        }
+
+        // Challenges are answered with delegate messages, and the server asks for them instead of its result
+        if !self.challenges.is_empty() && self.skip_delegation {
+            return Err(SrdError::MissingBlob);
+        }
+        if !self.challenges.is_empty() && !self.result_message {
+            return Err(SrdError::Proto("Challenges need the result message".to_owned()));
+        }
+
+        if self.challenges.iter().any(|blob_type| blob_type.is_empty() || blob_type.len() > 256) {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        if let Some(ref cert_data) = self.cert_data {
+            self.channel_binding.cbt_input(cert_data)?;
+        }
+
+        validate_ciphers(&self.ciphers)
+    }
+}
+
+// A custom group has to fit in the offer message: 2 bytes for the generator and a prime of one of the key sizes. The
+// safe prime test is the slow part, so it comes last.
+fn validate_dh_group(group: &DhGroup) -> Result<()> {
+    let generator = BigUint::from_bytes_be(&group.generator);
+    let prime = BigUint::from_bytes_be(&group.prime);
+
+    if generator < BigUint::from(2u8) || generator.bits() > 16 || generator >= prime {
+        return Err(SrdError::InvalidDhParams);
+    }
+
+    match prime.bits().div_ceil(8) {
+        256 | 512 | 1024 => {}
+        _ => return Err(SrdError::InvalidDhParams),
+    }
+
+    if !is_safe_prime(&prime)? {
+        return Err(SrdError::InvalidDhParams);
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_key_size(key_size: u16, min_key_size: u16) -> Result<()> {
+    match key_size {
+        256 | 512 | 1024 => {}
+        _ => return Err(SrdError::InvalidKeySize),
+    }
+
+    if key_size < min_key_size {
+        return Err(SrdError::InvalidKeySize);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_ciphers(ciphers: &[Cipher]) -> Result<()> {
+    if ciphers.is_empty() {
+        return Err(SrdError::Cipher);
+    }
+
+    let is_aes = |c: &Cipher| *c == Cipher::AES256 || *c == Cipher::AES256GCM;
+
+    if cfg!(feature = "fips") && !ciphers.iter().all(is_aes) {
+        return Err(SrdError::Cipher);
+    }
+
+    if cfg!(not(feature = "aes")) && ciphers.iter().any(is_aes) {
+        return Err(SrdError::Cipher);
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone)]
+pub struct SrdBuilder {
+    config: SrdConfig,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl SrdBuilder {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(is_server: bool) -> SrdBuilder {
+        SrdBuilder {
+            config: SrdConfig::new(is_server),
+        }
+    }
+
+    pub fn skip_delegation(mut self, skip_delegation: bool) -> SrdBuilder {
+        self.config.skip_delegation = skip_delegation;
+        self
+    }
+
+    // Diffie-Hellman key size in bytes: 256 (2048-bit), 512 (4096-bit) or 1024 (8192-bit)
+    pub fn key_size(mut self, key_size: u16) -> SrdBuilder {
+        self.config.key_size = key_size;
+        self
+    }
+
+    // Smallest key size accepted. A server rejects any client asking for less.
+    pub fn min_key_size(mut self, min_key_size: u16) -> SrdBuilder {
+        self.config.min_key_size = min_key_size;
+        self
+    }
+
+    // Use X25519 instead of Diffie-Hellman when the peer supports it. The client keeps proposing its Diffie-Hellman
+    // key size for servers that don't.
+    pub fn x25519(mut self, x25519: bool) -> SrdBuilder {
+        self.config.x25519 = x25519;
+        self
+    }
+
+    // Oldest protocol version accepted. Setting it above 1 refuses peers that don't support version negotiation.
+    pub fn min_version(mut self, min_version: u16) -> SrdBuilder {
+        self.config.min_version = min_version;
+        self
+    }
+
+    // Newest protocol version proposed by a client or accepted by a server
+    pub fn max_version(mut self, max_version: u16) -> SrdBuilder {
+        self.config.max_version = max_version;
+        self
+    }
+
+    // Secret known by both sides, like a one-time enrolment code. Both sides only get the same keys if they use the
+    // same secret, so a man-in-the-middle can't take part in the exchange without knowing it. Can't be used with
+    // X25519.
+    pub fn pake_secret(mut self, secret: Vec<u8>) -> SrdBuilder {
+        self.config.pake_secret = Some(secret);
+        self.config.pake = true;
+        self
+    }
+
+    // A client asks for a result message telling whether its delegated credentials were accepted, a server agrees to
+    // send it. Only used when both sides enable it.
+    pub fn result_message(mut self, result_message: bool) -> SrdBuilder {
+        self.config.result_message = result_message;
+        self
+    }
+
+    // Blob a server asks for with a challenge message once it has the delegated credentials, like "Otp". Challenges
+    // are asked in the order they are added, and need the result message: the config isn't built without it, and
+    // clients that don't ask for it are refused.
+    pub fn challenge(mut self, blob_type: &str) -> SrdBuilder {
+        self.config.challenges.push(blob_type.to_owned());
+        self
+    }
+
+    pub fn cert_data(mut self, cert_data: Vec<u8>) -> SrdBuilder {
+        self.config.cert_data = Some(cert_data);
+        self
+    }
+
+    // Channel binding data of the given type, instead of the raw certificate given to cert_data()
+    pub fn channel_binding(mut self, channel_binding: ChannelBinding, data: Vec<u8>) -> SrdBuilder {
+        self.config.channel_binding = channel_binding;
+        self.config.cert_data = Some(data);
+        self
+    }
+
+    pub fn cbt_policy(mut self, cbt_policy: CbtPolicy) -> SrdBuilder {
+        self.config.cbt_policy = Some(cbt_policy);
+        self
+    }
+
+    // Same as ciphers(), for bindings that can't pass a Vec<Cipher>
+    pub fn cipher_flags(mut self, flags: u32) -> SrdBuilder {
+        self.config.ciphers = Cipher::from_flags(flags);
+        self
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "wasm")] {
+        #[wasm_bindgen]
+        impl SrdBuilder {
+            pub fn build(self) -> std::result::Result<Srd, JsValue> {
+                let config = self.build_config().map_err(|e| JsValue::from_str(&e.to_string()))?;
+                Ok(Srd::from_config(config))
+            }
+        }
+    }
+    else {
+        impl SrdBuilder {
+            pub fn build(self) -> Result<Srd> {
+                Ok(Srd::from_config(self.build_config()?))
+            }
+        }
+    }
+}
+
+impl SrdBuilder {
+    // Applies a setter in place, without copying the builder
+    pub(crate) fn update<F: FnOnce(SrdBuilder) -> SrdBuilder>(&mut self, setter: F) {
+        let is_server = self.config.is_server;
+        let builder = std::mem::replace(self, SrdBuilder::new(is_server));
+        *self = setter(builder);
+    }
+
+    pub fn ciphers(mut self, ciphers: Vec<Cipher>) -> SrdBuilder {
+        self.config.ciphers = ciphers;
+        self
+    }
+
+    // Diffie-Hellman group accepted by a client on top of the well-known SRD groups. Generator and prime are big-endian.
+    // The prime has to be a safe prime, or the config can't be built.
+    pub fn allow_dh_group(mut self, generator: &[u8], prime: &[u8]) -> SrdBuilder {
+        self.config.dh_groups.push(DhGroup {
+            generator: generator.to_vec(),
+            prime: prime.to_vec(),
+        });
+        self
+    }
+
+    // Key a server signs its offer with, when a client asks for it
+    pub fn signing_key(mut self, signing_key: ServerSigningKey) -> SrdBuilder {
+        self.config.signing_key = Some(signing_key);
+        self
+    }
+
+    // A client with pinned keys only goes on with a server whose offer is signed by one of them
+    pub fn pin_server_key(mut self, public_key: ServerPublicKey) -> SrdBuilder {
+        self.config.pinned_keys.push(public_key);
+        self
+    }
+
+    // Cache a server records client nonces in, to reject an accept message it has already seen. Every context built
+    // from this builder shares the same cache.
+    pub fn nonce_cache(mut self, nonce_cache: Arc<dyn NonceCache>) -> SrdBuilder {
+        self.config.nonce_cache = Some(nonce_cache);
+        self
+    }
+
+    pub fn build_config(self) -> Result<SrdConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
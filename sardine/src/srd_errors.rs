@@ -7,8 +7,11 @@ use std::io::Error;
 use std::string::FromUtf8Error;
 
 use chacha;
+use chacha20poly1305::aead;
 use rand;
 
+use messages::srd_error_code::*;
+
 #[derive(Debug)]
 pub enum SrdError {
     Io(Error),
@@ -20,15 +23,21 @@ pub enum SrdError {
     Cipher,
     Rng,
     InvalidKeySize,
+    UnsupportedVersion,
+    InvalidDhParams,
+    InvalidPublicKey,
     InvalidMac,
     InvalidCbt,
     InvalidCert,
     InvalidCredentials,
+    UntrustedServer,
+    ReplayDetected,
     InvalidCstr,
     InvalidDataLength,
     InvalidSignature,
     UnknownMsgType,
     Proto(String),
+    PeerError(u16, String),
     Internal(String),
 }
 
@@ -44,20 +53,77 @@ impl fmt::Display for SrdError {
             &SrdError::Cipher => write!(f, "Cipher error"),
             &SrdError::Rng => write!(f, "RNG error"),
             &SrdError::InvalidKeySize => write!(f, "Key Size error"),
+            &SrdError::UnsupportedVersion => write!(f, "Protocol version error"),
+            &SrdError::InvalidDhParams => write!(f, "Diffie-Hellman parameters error"),
+            &SrdError::InvalidPublicKey => write!(f, "Public key error"),
             &SrdError::InvalidMac => write!(f, "MAC error"),
             &SrdError::InvalidCbt => write!(f, "CBT error"),
             &SrdError::InvalidCert => write!(f, "Certificate error"),
             &SrdError::InvalidCredentials => write!(f, "Credentials error"),
+            &SrdError::UntrustedServer => write!(f, "Server authentication error"),
+            &SrdError::ReplayDetected => write!(f, "Replay error"),
             &SrdError::InvalidCstr => write!(f, "String encoding error"),
             &SrdError::InvalidDataLength => write!(f, "Data length error"),
             &SrdError::InvalidSignature => write!(f, "Signature error"),
             &SrdError::UnknownMsgType => write!(f, "Unknown message type"),
             &SrdError::Proto(ref desc) => write!(f, "Protocol error: {}", desc),
+            &SrdError::PeerError(code, ref reason) => write!(f, "Error {} reported by the peer: {}", code, reason),
             &SrdError::Internal(ref desc) => write!(f, "Internal error: {}", desc),
         }
     }
 }
 
+impl SrdError {
+    // Code sent to the peer in an error message
+    pub fn code(&self) -> u16 {
+        match self {
+            &SrdError::BadSequence => SRD_ERROR_BAD_SEQUENCE,
+            &SrdError::UnsupportedVersion => SRD_ERROR_UNSUPPORTED_VERSION,
+            &SrdError::InvalidKeySize => SRD_ERROR_INVALID_KEY_SIZE,
+            &SrdError::InvalidDhParams => SRD_ERROR_INVALID_DH_PARAMS,
+            &SrdError::InvalidPublicKey => SRD_ERROR_INVALID_PUBLIC_KEY,
+            &SrdError::InvalidMac => SRD_ERROR_INVALID_MAC,
+            &SrdError::InvalidCbt => SRD_ERROR_INVALID_CBT,
+            &SrdError::InvalidCert => SRD_ERROR_INVALID_CERT,
+            &SrdError::MissingBlob => SRD_ERROR_MISSING_BLOB,
+            &SrdError::InvalidCredentials => SRD_ERROR_INVALID_CREDENTIALS,
+            &SrdError::Cipher => SRD_ERROR_CIPHER,
+            &SrdError::UntrustedServer => SRD_ERROR_UNTRUSTED_SERVER,
+            &SrdError::ReplayDetected => SRD_ERROR_REPLAY,
+            &SrdError::Crypto
+            | &SrdError::BlobFormatError
+            | &SrdError::InvalidCstr
+            | &SrdError::InvalidDataLength
+            | &SrdError::InvalidSignature
+            | &SrdError::UnknownMsgType
+            | &SrdError::Proto(_)
+            | &SrdError::PeerError(_, _) => SRD_ERROR_PROTOCOL,
+            &SrdError::Io(_) | &SrdError::Ffi(_) | &SrdError::Rng | &SrdError::Internal(_) => SRD_ERROR_INTERNAL,
+        }
+    }
+
+    // Status of a result message, as the error the server got. Unlike error messages, result messages are
+    // authenticated, so the status can be taken as one of our errors.
+    pub(crate) fn from_code(code: u16, reason: &str) -> SrdError {
+        match code {
+            SRD_ERROR_BAD_SEQUENCE => SrdError::BadSequence,
+            SRD_ERROR_UNSUPPORTED_VERSION => SrdError::UnsupportedVersion,
+            SRD_ERROR_INVALID_KEY_SIZE => SrdError::InvalidKeySize,
+            SRD_ERROR_INVALID_DH_PARAMS => SrdError::InvalidDhParams,
+            SRD_ERROR_INVALID_PUBLIC_KEY => SrdError::InvalidPublicKey,
+            SRD_ERROR_INVALID_MAC => SrdError::InvalidMac,
+            SRD_ERROR_INVALID_CBT => SrdError::InvalidCbt,
+            SRD_ERROR_INVALID_CERT => SrdError::InvalidCert,
+            SRD_ERROR_MISSING_BLOB => SrdError::MissingBlob,
+            SRD_ERROR_INVALID_CREDENTIALS => SrdError::InvalidCredentials,
+            SRD_ERROR_CIPHER => SrdError::Cipher,
+            SRD_ERROR_UNTRUSTED_SERVER => SrdError::UntrustedServer,
+            SRD_ERROR_REPLAY => SrdError::ReplayDetected,
+            code => SrdError::PeerError(code, reason.to_owned()),
+        }
+    }
+}
+
 impl std::error::Error for SrdError {}
 
 impl From<Error> for SrdError {
@@ -90,6 +156,12 @@ impl From<chacha::Error> for SrdError {
     }
 }
 
+impl From<aead::Error> for SrdError {
+    fn from(_error: aead::Error) -> SrdError {
+        SrdError::Crypto
+    }
+}
+
 impl From<rand::Error> for SrdError {
     fn from(_error: rand::Error) -> SrdError {
         SrdError::Rng
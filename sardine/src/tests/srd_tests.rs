@@ -1,6 +1,15 @@
-use blobs::{BasicBlob, LogonBlob};
+use channel_binding::ChannelBinding;
+use blobs::{BasicBlob, ChangeBlob, LogonBlob, SrdBlob, SRD_CHANGE_BLOB_FLAG_LOGON};
 use cipher::Cipher;
-use srd::Srd;
+use messages::{new_srd_initiate_msg, Message, SrdMessage, SRD_VERSION_1, SRD_VERSION_2};
+use nonce_cache::{MemoryNonceCache, NonceCache};
+use num_bigint::BigUint;
+use srd::{KeyExchange, Srd, SrdOutcome, SrdState};
+use server_key::{ServerSigningKey, SignatureAlgorithm};
+use srd_config::{CbtPolicy, SrdBuilder};
+use std::sync::Arc;
+use std::time::Duration;
+use SrdError;
 
 static TEST_CERT_DATA: &'static [u8] = b"\x30\x82\x02\xfa\x30\x82\x01\xe2\xa0\x03\x02\x01\x02\x02\x10\x16
 	\xed\x2a\xa0\x49\x5f\x25\x9d\x4f\x5d\x99\xed\xad\xa5\x70\xd1\x30
@@ -151,6 +160,38 @@ fn good_login_logon_blob() {
     assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
 }
 
+#[test]
+fn good_login_change_blob() {
+    let mut client: Srd = Srd::new(false, false);
+    let mut server: Srd = Srd::new(true, false);
+
+    let mut in_data: Vec<u8> = Vec::new();
+    let mut out_data: Vec<u8> = Vec::new();
+
+    client.set_cert_data(TEST_CERT_DATA.to_vec()).unwrap();
+    server.set_cert_data(TEST_CERT_DATA.to_vec()).unwrap();
+
+    let change_blob = ChangeBlob::new("fdubois", "Dummy123", "1234567ßẞ", SRD_CHANGE_BLOB_FLAG_LOGON);
+    client.set_blob(change_blob.clone()).unwrap();
+
+    let mut client_status: bool = false;
+    let mut server_status: bool = false;
+
+    while !(client_status && server_status) {
+        client_status = client.authenticate(&in_data, &mut out_data).unwrap();
+        in_data = out_data;
+        out_data = Vec::new();
+
+        server_status = server.authenticate(&in_data, &mut out_data).unwrap();
+        in_data = out_data;
+        out_data = Vec::new();
+    }
+
+    assert_eq!(server.get_raw_blob().unwrap().blob_type(), "Change");
+    assert!(server.get_blob::<LogonBlob>().unwrap().is_none());
+    assert_eq!(server.get_blob::<ChangeBlob>().unwrap().unwrap(), change_blob);
+}
+
 #[test]
 fn skip_delegation() {
     let mut client = Srd::new(false, true);
@@ -180,4 +221,1336 @@ fn skip_delegation() {
 
     assert!(client_status);
     assert!(server_status);
-}
\ No newline at end of file
+}
+#[test]
+fn handshake_states() {
+    let mut client = Srd::new(false, false);
+    let mut server = Srd::new(true, false);
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    assert_eq!(client.state(), SrdState::Initial);
+    assert_eq!(server.state(), SrdState::Initial);
+
+    let mut initiate = Vec::new();
+    assert!(!client.authenticate(&[], &mut initiate).unwrap());
+    assert_eq!(client.state(), SrdState::AwaitingOffer);
+
+    let mut offer = Vec::new();
+    assert!(!server.authenticate(&initiate, &mut offer).unwrap());
+    assert_eq!(server.state(), SrdState::AwaitingAccept);
+
+    let mut accept = Vec::new();
+    assert!(!client.authenticate(&offer, &mut accept).unwrap());
+    assert_eq!(client.state(), SrdState::AwaitingConfirm);
+
+    let mut confirm = Vec::new();
+    assert!(!server.authenticate(&accept, &mut confirm).unwrap());
+    assert_eq!(server.state(), SrdState::AwaitingDelegate);
+
+    let mut delegate = Vec::new();
+    assert!(client.authenticate(&confirm, &mut delegate).unwrap());
+    assert_eq!(client.state(), SrdState::Complete);
+
+    assert!(server.authenticate(&delegate, &mut Vec::new()).unwrap());
+    assert_eq!(server.state(), SrdState::Complete);
+
+    // Nothing is expected once the handshake is complete, but the session isn't lost
+    match server.authenticate(&delegate, &mut Vec::new()) {
+        Err(SrdError::BadSequence) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(server.state(), SrdState::Complete);
+    assert!(server.secure_channel().is_ok());
+}
+
+#[test]
+fn failed_context_is_poisoned() {
+    let mut client = Srd::new(false, false);
+    let mut server = Srd::new(true, false);
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+
+    // A truncated message makes the server fail...
+    assert!(server.authenticate(&initiate[..4], &mut Vec::new()).is_err());
+    assert_eq!(server.state(), SrdState::Failed);
+
+    // ... and a valid message afterwards doesn't bring it back
+    assert!(server.authenticate(&initiate, &mut Vec::new()).is_err());
+    assert_eq!(server.state(), SrdState::Failed);
+}
+
+// Runs a full exchange, client first, until both sides are done
+fn run_handshake(client: &mut Srd, server: &mut Srd) -> ::Result<()> {
+    let mut in_data: Vec<u8> = Vec::new();
+    let mut out_data: Vec<u8> = Vec::new();
+
+    let mut client_status: bool = false;
+    let mut server_status: bool = false;
+
+    while !(client_status && server_status) {
+        client_status = client.authenticate(&in_data, &mut out_data)?;
+        in_data = out_data;
+        out_data = Vec::new();
+
+        if client_status && in_data.is_empty() {
+            break;
+        }
+
+        server_status = server.authenticate(&in_data, &mut out_data)?;
+        in_data = out_data;
+        out_data = Vec::new();
+    }
+
+    Ok(())
+}
+
+#[test]
+fn builder_login() {
+    let client_config = SrdBuilder::new(false)
+        .key_size(512)
+        .ciphers(vec![Cipher::ChaCha20])
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .build_config()
+        .unwrap();
+    let server_builder = SrdBuilder::new(true)
+        .ciphers(vec![Cipher::ChaCha20, Cipher::XChaCha20])
+        .cert_data(TEST_CERT_DATA.to_vec());
+
+    // The same config can be used for several contexts
+    for _ in 0..2 {
+        let mut client = Srd::from_config(client_config.clone());
+        let mut server = server_builder.clone().build().unwrap();
+
+        let logon_blob = LogonBlob::new("fdubois", "Dummy123");
+        client.set_blob(logon_blob.clone()).unwrap();
+
+        run_handshake(&mut client, &mut server).unwrap();
+
+        assert!(client.get_cipher() == Cipher::ChaCha20);
+        assert!(server.get_cipher() == Cipher::ChaCha20);
+        assert_eq!(client.get_keys(), server.get_keys());
+        assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
+    }
+}
+
+#[test]
+fn aead_ciphers_login() {
+    let mut ciphers = vec![Cipher::XChaCha20Poly1305, Cipher::ChaCha20Poly1305];
+    if cfg!(feature = "aes") {
+        ciphers.push(Cipher::AES256GCM);
+    }
+
+    for cipher in ciphers {
+        let mut client = SrdBuilder::new(false).ciphers(vec![cipher]).build().unwrap();
+        let mut server = SrdBuilder::new(true).build().unwrap();
+
+        let logon_blob = LogonBlob::new("fdubois", "Dummy123");
+        client.set_blob(logon_blob.clone()).unwrap();
+
+        run_handshake(&mut client, &mut server).unwrap();
+
+        assert!(client.get_cipher() == cipher);
+        assert!(server.get_cipher() == cipher);
+        assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
+    }
+}
+
+#[test]
+fn aead_cipher_preferred() {
+    let mut client = SrdBuilder::new(false).build().unwrap();
+    let mut server = SrdBuilder::new(true)
+        .ciphers(vec![Cipher::ChaCha20, Cipher::ChaCha20Poly1305])
+        .build()
+        .unwrap();
+
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert!(client.get_cipher() == Cipher::ChaCha20Poly1305);
+    assert!(server.get_cipher() == Cipher::ChaCha20Poly1305);
+}
+
+#[test]
+fn hkdf_key_schedule() {
+    let mut client = Srd::new(false, false);
+    let mut server = Srd::new(true, false);
+
+    let logon_blob = LogonBlob::new("fdubois", "Dummy123");
+    client.set_blob(logon_blob.clone()).unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    match SrdMessage::read_from(&mut initiate.as_slice()).unwrap() {
+        SrdMessage::Initiate(hdr, msg) => {
+            assert!(hdr.has_version());
+            assert_eq!(msg.version(), SRD_VERSION_2);
+        }
+        _ => assert!(false),
+    }
+
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+    let mut accept = Vec::new();
+    client.authenticate(&offer, &mut accept).unwrap();
+    let mut confirm = Vec::new();
+    server.authenticate(&accept, &mut confirm).unwrap();
+    let mut delegate = Vec::new();
+    assert!(client.authenticate(&confirm, &mut delegate).unwrap());
+    assert!(server.authenticate(&delegate, &mut Vec::new()).unwrap());
+
+    assert_eq!(client.get_keys(), server.get_keys());
+    assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
+}
+
+#[test]
+fn legacy_client_gets_legacy_offer() {
+    let mut server = Srd::new(true, false);
+
+    // What a version 1 client sends: no version flag and a zero version field
+    let mut initiate = Vec::new();
+    new_srd_initiate_msg(0, false, Cipher::ChaCha20.flag(), 256, false)
+        .unwrap()
+        .write_to(&mut initiate)
+        .unwrap();
+
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+
+    match SrdMessage::read_from(&mut offer.as_slice()).unwrap() {
+        SrdMessage::Offer(hdr, msg) => {
+            assert!(!hdr.has_version());
+            assert_eq!(msg.version(), SRD_VERSION_1);
+            assert_eq!(offer.len(), 8 + 4 + 2 + 2 + 256 + 256 + 32);
+        }
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn version_negotiation() {
+    // Highest version supported by both sides, whichever side is older
+    let configs = vec![
+        (SrdBuilder::new(false).max_version(SRD_VERSION_1), SrdBuilder::new(true), SRD_VERSION_1),
+        (SrdBuilder::new(false), SrdBuilder::new(true).max_version(SRD_VERSION_1), SRD_VERSION_1),
+        (SrdBuilder::new(false), SrdBuilder::new(true), SRD_VERSION_2),
+    ];
+
+    for (client_builder, server_builder, version) in configs {
+        let mut client = client_builder.skip_delegation(true).build().unwrap();
+        let mut server = server_builder.skip_delegation(true).build().unwrap();
+
+        run_handshake(&mut client, &mut server).unwrap();
+
+        assert_eq!(client.version(), version);
+        assert_eq!(server.version(), version);
+        assert_eq!(client.get_keys(), server.get_keys());
+    }
+}
+
+#[test]
+fn min_version() {
+    let mut client = SrdBuilder::new(false).min_version(SRD_VERSION_2).build().unwrap();
+    let mut server = SrdBuilder::new(true).max_version(SRD_VERSION_1).build().unwrap();
+
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::UnsupportedVersion) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(client.state(), SrdState::Failed);
+
+    let mut client = SrdBuilder::new(false).max_version(SRD_VERSION_1).build().unwrap();
+    let mut server = SrdBuilder::new(true).min_version(SRD_VERSION_2).build().unwrap();
+
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::UnsupportedVersion) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(server.state(), SrdState::Failed);
+
+    assert!(SrdBuilder::new(false).min_version(0).build().is_err());
+    assert!(SrdBuilder::new(false).max_version(SRD_VERSION_2 + 1).build().is_err());
+    assert!(SrdBuilder::new(false)
+        .min_version(SRD_VERSION_2)
+        .max_version(SRD_VERSION_1)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn version_downgrade_detected() {
+    let mut client = Srd::new(false, false);
+    let mut server = Srd::new(true, false);
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+
+    // Strip the version, as if the client was a version 1 client
+    let cipher_flags = client.config().ciphers().iter().fold(0, |flags, c| flags | c.flag());
+    let initiate = tamper_msg(&initiate, |msg| {
+        *msg = new_srd_initiate_msg(0, false, cipher_flags, 256, false).unwrap();
+    });
+
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+    assert_eq!(server.version(), SRD_VERSION_1);
+
+    let mut accept = Vec::new();
+    client.authenticate(&offer, &mut accept).unwrap();
+    assert_eq!(client.version(), SRD_VERSION_1);
+
+    match server.authenticate(&accept, &mut Vec::new()) {
+        Err(SrdError::InvalidMac) => {}
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn server_requires_cbt() {
+    // A client without CBT, or an initiate message with SRD_FLAG_CBT stripped
+    let mut client = SrdBuilder::new(false).skip_delegation(true).build().unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .build()
+        .unwrap();
+
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::InvalidCbt) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(server.state(), SrdState::Failed);
+}
+
+#[test]
+fn client_requires_cbt() {
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true).skip_delegation(true).build().unwrap();
+
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::InvalidCbt) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(client.state(), SrdState::Failed);
+}
+
+#[test]
+fn optional_cbt() {
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .cbt_policy(CbtPolicy::Optional)
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true).skip_delegation(true).build().unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    let mut client = SrdBuilder::new(false).skip_delegation(true).build().unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .cbt_policy(CbtPolicy::Disabled)
+        .build()
+        .unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    // Optional still means CBT when both sides have certificate data, so a mismatch is detected
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .cert_data(b"other certificate".to_vec())
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .cbt_policy(CbtPolicy::Optional)
+        .build()
+        .unwrap();
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::InvalidCbt) => {}
+        _ => assert!(false),
+    }
+
+    // Required needs certificate data
+    assert!(SrdBuilder::new(true).cbt_policy(CbtPolicy::Required).build().is_err());
+    let mut server = Srd::new(true, false);
+    server.set_cbt_policy(CbtPolicy::Required).unwrap();
+    assert!(server.authenticate(&[], &mut Vec::new()).is_err());
+}
+
+#[test]
+fn channel_binding_types() {
+    // Just enough of a certificate for tls-server-end-point: no TBSCertificate, signed with ecdsa-with-SHA384
+    let cert = b"\x30\x11\x30\x00\x30\x0a\x06\x08\x2a\x86\x48\xce\x3d\x04\x03\x03\x03\x01\x00";
+    let exporter = [7u8; 32];
+
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .channel_binding(ChannelBinding::TlsServerEndPoint, cert.to_vec())
+        .build()
+        .unwrap();
+    let mut server = Srd::new(true, true);
+    server
+        .set_channel_binding(ChannelBinding::TlsServerEndPoint, cert.to_vec())
+        .unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .channel_binding(ChannelBinding::TlsExporter, exporter.to_vec())
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .channel_binding(ChannelBinding::TlsExporter, exporter.to_vec())
+        .build()
+        .unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    // Same data, different types
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .channel_binding(ChannelBinding::TlsExporter, exporter.to_vec())
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .channel_binding(ChannelBinding::TlsUnique, exporter.to_vec())
+        .build()
+        .unwrap();
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::InvalidCbt) => {}
+        _ => assert!(false),
+    }
+
+    // tls-server-end-point needs a certificate
+    assert!(SrdBuilder::new(false)
+        .channel_binding(ChannelBinding::TlsServerEndPoint, exporter.to_vec())
+        .build()
+        .is_err());
+}
+
+#[test]
+fn pinned_server_key() {
+    for algorithm in vec![SignatureAlgorithm::Ed25519, SignatureAlgorithm::EcdsaP256] {
+        let signing_key = ServerSigningKey::new(algorithm, &[9u8; 32]).unwrap();
+
+        let mut client = SrdBuilder::new(false)
+            .pin_server_key(ServerSigningKey::new(algorithm, &[8u8; 32]).unwrap().public_key().unwrap())
+            .pin_server_key(signing_key.public_key().unwrap())
+            .build()
+            .unwrap();
+        let mut server = SrdBuilder::new(true).signing_key(signing_key).build().unwrap();
+
+        let logon_blob = LogonBlob::new("fdubois", "Dummy123");
+        client.set_blob(logon_blob.clone()).unwrap();
+
+        run_handshake(&mut client, &mut server).unwrap();
+        assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
+    }
+
+    // A client that doesn't pin anything doesn't ask for a signature
+    let mut client = SrdBuilder::new(false).skip_delegation(true).build().unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .signing_key(ServerSigningKey::new(SignatureAlgorithm::Ed25519, &[9u8; 32]).unwrap())
+        .build()
+        .unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn untrusted_server_key() {
+    let pinned_key = ServerSigningKey::new(SignatureAlgorithm::Ed25519, &[9u8; 32]).unwrap().public_key().unwrap();
+    let servers = vec![
+        // Wrong key, wrong algorithm, no key
+        SrdBuilder::new(true).signing_key(ServerSigningKey::new(SignatureAlgorithm::Ed25519, &[8u8; 32]).unwrap()),
+        SrdBuilder::new(true).signing_key(ServerSigningKey::new(SignatureAlgorithm::EcdsaP256, &[9u8; 32]).unwrap()),
+        SrdBuilder::new(true),
+    ];
+
+    for server_builder in servers {
+        let mut client = SrdBuilder::new(false).pin_server_key(pinned_key.clone()).build().unwrap();
+        let mut server = server_builder.build().unwrap();
+        client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+        match run_handshake(&mut client, &mut server) {
+            Err(SrdError::UntrustedServer) => {}
+            _ => assert!(false),
+        }
+        assert_eq!(client.state(), SrdState::Failed);
+    }
+
+    // Signed by the right key, but modified on the way
+    let mut client = SrdBuilder::new(false).pin_server_key(pinned_key).build().unwrap();
+    let mut server = SrdBuilder::new(true)
+        .signing_key(ServerSigningKey::new(SignatureAlgorithm::Ed25519, &[9u8; 32]).unwrap())
+        .build()
+        .unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+
+    let offer = tamper_msg(&offer, |msg| match msg {
+        SrdMessage::Offer(_, ref mut offer) => offer.nonce = [0u8; 32],
+        _ => panic!("Not an offer"),
+    });
+    match client.authenticate(&offer, &mut Vec::new()) {
+        Err(SrdError::UntrustedServer) => {}
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn pake_login() {
+    let mut client = SrdBuilder::new(false).pake_secret(b"493-118".to_vec()).build().unwrap();
+    let mut server = SrdBuilder::new(true).pake_secret(b"493-118".to_vec()).build().unwrap();
+
+    let logon_blob = LogonBlob::new("fdubois", "Dummy123");
+    client.set_blob(logon_blob.clone()).unwrap();
+
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert_eq!(client.get_keys(), server.get_keys());
+    assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
+
+    // PAKE needs Diffie-Hellman
+    assert!(SrdBuilder::new(true)
+        .x25519(true)
+        .pake_secret(b"493-118".to_vec())
+        .build()
+        .is_err());
+    assert!(SrdBuilder::new(true).pake_secret(Vec::new()).build().is_err());
+}
+
+#[test]
+fn pake_wrong_secret() {
+    let mut client = SrdBuilder::new(false).pake_secret(b"493-118".to_vec()).build().unwrap();
+    let mut server = SrdBuilder::new(true).pake_secret(b"493-119".to_vec()).build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::InvalidCredentials) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(server.state(), SrdState::Failed);
+
+    // PAKE on one side only
+    let configs = vec![
+        (SrdBuilder::new(false).pake_secret(b"493-118".to_vec()), SrdBuilder::new(true)),
+        (SrdBuilder::new(false), SrdBuilder::new(true).pake_secret(b"493-118".to_vec())),
+    ];
+    for (client_builder, server_builder) in configs {
+        let mut client = client_builder.build().unwrap();
+        let mut server = server_builder.build().unwrap();
+        client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+        match run_handshake(&mut client, &mut server) {
+            Err(SrdError::InvalidCredentials) => {}
+            _ => assert!(false),
+        }
+    }
+}
+
+#[test]
+fn builder_skip_delegation() {
+    let mut client = SrdBuilder::new(false).skip_delegation(true).build().unwrap();
+    let mut server = SrdBuilder::new(true).skip_delegation(true).build().unwrap();
+
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert_eq!(client.state(), SrdState::Complete);
+    assert_eq!(server.state(), SrdState::Complete);
+    assert_eq!(client.get_keys(), server.get_keys());
+}
+
+#[test]
+fn builder_rejects_invalid_config() {
+    match SrdBuilder::new(false).key_size(300).build() {
+        Err(SrdError::InvalidKeySize) => {}
+        _ => assert!(false),
+    }
+
+    match SrdBuilder::new(true).ciphers(Vec::new()).build() {
+        Err(SrdError::Cipher) => {}
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn client_key_size() {
+    let mut client = Srd::new(false, true);
+    let mut server = SrdBuilder::new(true).skip_delegation(true).min_key_size(512).build().unwrap();
+
+    client.set_key_size(512).unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert_eq!(client.get_key_size(), 512);
+    assert_eq!(server.get_key_size(), 512);
+    assert_eq!(client.get_keys(), server.get_keys());
+
+    // Can't be changed once the handshake has started
+    assert!(client.set_key_size(256).is_err());
+}
+
+#[test]
+fn server_min_key_size() {
+    let mut client = SrdBuilder::new(false).skip_delegation(true).key_size(256).build().unwrap();
+    let mut server = SrdBuilder::new(true).skip_delegation(true).min_key_size(512).build().unwrap();
+
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::InvalidKeySize) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(server.state(), SrdState::Failed);
+
+    // A client can't go below its own minimum either
+    assert!(SrdBuilder::new(false).key_size(256).min_key_size(512).build().is_err());
+}
+
+// Lets a test play man-in-the-middle on a message
+fn tamper_msg<F: FnOnce(&mut SrdMessage)>(buffer: &[u8], f: F) -> Vec<u8> {
+    let mut msg = SrdMessage::read_from(&mut std::io::Cursor::new(buffer)).unwrap();
+    f(&mut msg);
+
+    let mut buffer = Vec::new();
+    msg.write_to(&mut buffer).unwrap();
+    buffer
+}
+
+fn tamper_offer<F: FnOnce(&mut Vec<u8>, &mut Vec<u8>)>(offer: &[u8], f: F) -> Vec<u8> {
+    tamper_msg(offer, |msg| match msg {
+        SrdMessage::Offer(_, ref mut offer) => f(&mut offer.generator, &mut offer.prime),
+        _ => panic!("Not an offer"),
+    })
+}
+
+#[test]
+fn client_rejects_unknown_dh_group() {
+    let mut client = Srd::new(false, false);
+    let mut server = Srd::new(true, false);
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+
+    // Even number, so not a prime
+    let offer = tamper_offer(&offer, |_, prime| {
+        let last = prime.len() - 1;
+        prime[last] ^= 1;
+    });
+
+    match client.authenticate(&offer, &mut Vec::new()) {
+        Err(SrdError::InvalidDhParams) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(client.state(), SrdState::Failed);
+}
+
+#[test]
+fn client_accepts_allowed_dh_group() {
+    let mut server = Srd::new(true, false);
+    let mut initiate = Vec::new();
+    Srd::new(false, false).authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+
+    let mut prime = Vec::new();
+    let offer = tamper_offer(&offer, |generator, offer_prime| {
+        *generator = vec![0, 5];
+        prime = offer_prime.clone();
+    });
+
+    let mut client = Srd::new(false, false);
+    client.authenticate(&[], &mut Vec::new()).unwrap();
+    match client.authenticate(&offer, &mut Vec::new()) {
+        Err(SrdError::InvalidDhParams) => {}
+        _ => assert!(false),
+    }
+
+    let mut client = SrdBuilder::new(false).allow_dh_group(&[5], &prime).build().unwrap();
+    client.authenticate(&[], &mut Vec::new()).unwrap();
+    client.authenticate(&offer, &mut Vec::new()).unwrap();
+    assert_eq!(client.state(), SrdState::AwaitingConfirm);
+
+    // Only safe primes can be allowed
+    let not_safe = (BigUint::from_bytes_be(&prime) - BigUint::from(2u8)).to_bytes_be();
+    match SrdBuilder::new(false).allow_dh_group(&[5], &not_safe).build() {
+        Err(SrdError::InvalidDhParams) => {}
+        _ => assert!(false),
+    }
+}
+
+// Public keys a peer must never accept: 0, 1 and p-1, padded to the key size like on the wire
+fn degenerate_public_keys(prime: &[u8]) -> Vec<Vec<u8>> {
+    let p_minus_one = (BigUint::from_bytes_be(prime) - BigUint::from(1u8)).to_bytes_be();
+
+    vec![vec![0u8; prime.len()], vec![1u8], p_minus_one]
+        .into_iter()
+        .map(|key| {
+            let mut padded = vec![0u8; prime.len() - key.len()];
+            padded.extend_from_slice(&key);
+            padded
+        })
+        .collect()
+}
+
+#[test]
+fn client_rejects_degenerate_public_key() {
+    let mut initiate = Vec::new();
+    Srd::new(false, false).authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    Srd::new(true, false).authenticate(&initiate, &mut offer).unwrap();
+
+    let mut prime = Vec::new();
+    tamper_offer(&offer, |_, offer_prime| prime = offer_prime.clone());
+
+    for public_key in degenerate_public_keys(&prime) {
+        let offer = tamper_msg(&offer, |msg| match msg {
+            SrdMessage::Offer(_, ref mut offer) => offer.public_key = public_key,
+            _ => panic!("Not an offer"),
+        });
+
+        let mut client = Srd::new(false, false);
+        client.authenticate(&[], &mut Vec::new()).unwrap();
+        match client.authenticate(&offer, &mut Vec::new()) {
+            Err(SrdError::InvalidPublicKey) => {}
+            _ => assert!(false),
+        }
+    }
+}
+
+#[test]
+fn server_rejects_degenerate_public_key() {
+    let mut client = Srd::new(false, false);
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+
+    let mut offers = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..3 {
+        let mut server = Srd::new(true, false);
+        let mut offer = Vec::new();
+        server.authenticate(&initiate, &mut offer).unwrap();
+        servers.push(server);
+        offers.push(offer);
+    }
+
+    let mut accept = Vec::new();
+    client.authenticate(&offers[0], &mut accept).unwrap();
+
+    let mut prime = Vec::new();
+    tamper_offer(&offers[0], |_, offer_prime| prime = offer_prime.clone());
+
+    for (server, public_key) in servers.iter_mut().zip(degenerate_public_keys(&prime)) {
+        let accept = tamper_msg(&accept, |msg| match msg {
+            SrdMessage::Accept(_, ref mut accept) => accept.public_key = public_key,
+            _ => panic!("Not an accept"),
+        });
+
+        match server.authenticate(&accept, &mut Vec::new()) {
+            Err(SrdError::InvalidPublicKey) => {}
+            _ => assert!(false),
+        }
+    }
+}
+
+#[test]
+fn x25519_login() {
+    let mut client = SrdBuilder::new(false)
+        .x25519(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true)
+        .x25519(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .build()
+        .unwrap();
+
+    let logon_blob = LogonBlob::new("fdubois", "Dummy123");
+    client.set_blob(logon_blob.clone()).unwrap();
+
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert_eq!(client.get_key_exchange(), KeyExchange::X25519);
+    assert_eq!(server.get_key_exchange(), KeyExchange::X25519);
+    assert_eq!(client.get_keys(), server.get_keys());
+    assert_eq!(server.get_blob::<LogonBlob>().unwrap().unwrap(), logon_blob);
+}
+
+#[test]
+fn x25519_falls_back_to_dh() {
+    // Server without X25519, like an older server
+    let mut client = SrdBuilder::new(false).x25519(true).skip_delegation(true).build().unwrap();
+    let mut server = Srd::new(true, true);
+
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert_eq!(client.get_key_exchange(), KeyExchange::Dh);
+    assert_eq!(server.get_key_exchange(), KeyExchange::Dh);
+    assert_eq!(client.get_keys(), server.get_keys());
+
+    // Client without X25519, like an older client
+    let mut client = Srd::new(false, true);
+    let mut server = SrdBuilder::new(true).x25519(true).skip_delegation(true).build().unwrap();
+
+    run_handshake(&mut client, &mut server).unwrap();
+
+    assert_eq!(client.get_key_exchange(), KeyExchange::Dh);
+    assert_eq!(server.get_key_exchange(), KeyExchange::Dh);
+    assert_eq!(client.get_keys(), server.get_keys());
+}
+
+#[test]
+fn x25519_rejects_low_order_public_key() {
+    let mut client = SrdBuilder::new(false).x25519(true).build().unwrap();
+    let mut server = SrdBuilder::new(true).x25519(true).build().unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+
+    let offer = tamper_msg(&offer, |msg| match msg {
+        SrdMessage::Offer(_, ref mut offer) => offer.public_key = vec![0u8; 32],
+        _ => panic!("Not an offer"),
+    });
+
+    match client.authenticate(&offer, &mut Vec::new()) {
+        Err(SrdError::InvalidPublicKey) => {}
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn secure_channel() {
+    for (ciphers, skip_delegation, version) in vec![
+        (vec![Cipher::ChaCha20], false, SRD_VERSION_1),
+        (vec![Cipher::XChaCha20Poly1305], true, SRD_VERSION_2),
+    ] {
+        let mut client = SrdBuilder::new(false)
+            .ciphers(ciphers)
+            .skip_delegation(skip_delegation)
+            .max_version(version)
+            .build()
+            .unwrap();
+        let mut server = SrdBuilder::new(true).skip_delegation(skip_delegation).build().unwrap();
+
+        assert!(client.secure_channel().is_err());
+
+        if !skip_delegation {
+            client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+        }
+        run_handshake(&mut client, &mut server).unwrap();
+
+        let mut client_channel = client.secure_channel().unwrap();
+        let mut server_channel = server.secure_channel().unwrap();
+        assert!(client_channel.cipher() == client.get_cipher().aead());
+
+        // A second channel would reuse the nonces of the first one
+        match client.secure_channel() {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+
+        let record = client_channel.seal(b"command").unwrap();
+        assert_eq!(server_channel.open(&record).unwrap(), b"command".to_vec());
+        let record = server_channel.seal(b"response").unwrap();
+        assert_eq!(client_channel.open(&record).unwrap(), b"response".to_vec());
+
+        // Another handshake gives other keys
+        let mut other_client = SrdBuilder::new(false).skip_delegation(true).build().unwrap();
+        let mut other_server = SrdBuilder::new(true).skip_delegation(true).build().unwrap();
+        run_handshake(&mut other_client, &mut other_server).unwrap();
+
+        let record = client_channel.seal(b"command").unwrap();
+        assert!(other_server.secure_channel().unwrap().open(&record).is_err());
+    }
+}
+
+#[test]
+fn export_keying_material() {
+    let mut client = SrdBuilder::new(false).skip_delegation(true).build().unwrap();
+    let mut server = SrdBuilder::new(true).skip_delegation(true).build().unwrap();
+
+    match client.export_keying_material("EXPORTER-test", b"", 32) {
+        Err(SrdError::BadSequence) => {}
+        _ => assert!(false),
+    }
+
+    run_handshake(&mut client, &mut server).unwrap();
+
+    let key = client.export_keying_material("EXPORTER-test", b"context", 32).unwrap();
+    assert_eq!(key, server.export_keying_material("EXPORTER-test", b"context", 32).unwrap());
+    assert!(key.as_slice() != client.get_delegation_key().as_slice());
+    assert!(key.as_slice() != client.get_integrity_key().as_slice());
+
+    let long_key = client.export_keying_material("EXPORTER-test", b"context", 100).unwrap();
+    assert_eq!(long_key, server.export_keying_material("EXPORTER-test", b"context", 100).unwrap());
+    assert!(&long_key[..32] != key.as_slice());
+
+    assert!(client.export_keying_material("EXPORTER-other", b"context", 32).unwrap() != key);
+    assert!(client.export_keying_material("EXPORTER-test", b"other", 32).unwrap() != key);
+    assert!(client.export_keying_material("EXPORTER-test", b"", 32).unwrap() != key);
+
+    assert!(client.export_keying_material("", b"context", 32).is_err());
+    assert!(client.export_keying_material("EXPORTER-test", b"context", 0).is_err());
+    assert!(client.export_keying_material("EXPORTER-test", b"context", 10000).is_err());
+}
+
+#[cfg(feature = "ser")]
+#[test]
+fn restored_context() {
+    use bincode;
+
+    // Before the handshake, a restored context can run it
+    let mut client: Srd = bincode::deserialize(&bincode::serialize(&Srd::new(false, true)).unwrap()).unwrap();
+    let mut server = SrdBuilder::new(true).skip_delegation(true).build().unwrap();
+    run_handshake(&mut client, &mut server).unwrap();
+
+    // Once it is complete, a restored context has the same keys
+    let mut restored: Srd = bincode::deserialize(&bincode::serialize(&server).unwrap()).unwrap();
+    let key = client.export_keying_material("EXPORTER-test", b"context", 32).unwrap();
+    assert_eq!(restored.export_keying_material("EXPORTER-test", b"context", 32).unwrap(), key);
+    let record = client.secure_channel().unwrap().seal(b"command").unwrap();
+    assert_eq!(restored.secure_channel().unwrap().open(&record).unwrap(), b"command".to_vec());
+
+    // In the middle of the handshake, the transcript can't be restored
+    let mut client = Srd::new(false, true);
+    let mut server = Srd::new(true, true);
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+    let mut accept = Vec::new();
+    client.authenticate(&offer, &mut accept).unwrap();
+
+    let mut restored: Srd = bincode::deserialize(&bincode::serialize(&server).unwrap()).unwrap();
+    match restored.authenticate(&accept, &mut Vec::new()) {
+        Err(SrdError::BadSequence) => {}
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn error_message() {
+    let mut client = SrdBuilder::new(false)
+        .skip_delegation(true)
+        .cert_data(TEST_CERT_DATA.to_vec())
+        .build()
+        .unwrap();
+    let mut server = SrdBuilder::new(true)
+        .skip_delegation(true)
+        .cert_data(b"another certificate".to_vec())
+        .build()
+        .unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+    let mut accept = Vec::new();
+    client.authenticate(&offer, &mut accept).unwrap();
+
+    // The server tells the client why it failed instead of not answering
+    let mut error = Vec::new();
+    match server.authenticate(&accept, &mut error) {
+        Err(SrdError::InvalidCbt) => {}
+        _ => assert!(false),
+    }
+
+    match SrdMessage::read_from(&mut std::io::Cursor::new(error.as_slice())).unwrap() {
+        SrdMessage::Error(_, error) => assert_eq!(error.code(), SrdError::InvalidCbt.code()),
+        _ => assert!(false),
+    }
+
+    // ...and the client doesn't answer it. Error messages aren't authenticated, so the client doesn't take it as a CBT
+    // failure of its own.
+    let mut output = Vec::new();
+    match client.authenticate(&error, &mut output) {
+        Err(SrdError::PeerError(code, ref reason)) if code == SrdError::InvalidCbt.code() && reason == "CBT error" => {}
+        _ => assert!(false),
+    }
+    assert!(output.is_empty());
+    assert_eq!(client.state(), SrdState::Failed);
+
+    // A failed context doesn't answer anymore
+    assert!(server.authenticate(&accept, &mut output).is_err());
+    assert!(output.is_empty());
+}
+
+fn result_handshake(client_result: bool, server_result: bool) -> (Srd, Srd, Vec<u8>) {
+    let mut client = SrdBuilder::new(false).result_message(client_result).build().unwrap();
+    let mut server = SrdBuilder::new(true).result_message(server_result).build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+    let mut accept = Vec::new();
+    client.authenticate(&offer, &mut accept).unwrap();
+    let mut confirm = Vec::new();
+    server.authenticate(&accept, &mut confirm).unwrap();
+    let mut delegate = Vec::new();
+    let client_complete = client.authenticate(&confirm, &mut delegate).unwrap();
+    assert_eq!(client_complete, !(client_result && server_result));
+
+    (client, server, delegate)
+}
+
+#[test]
+fn result_message() {
+    for &accepted in &[true, false] {
+        let (mut client, mut server, delegate) = result_handshake(true, true);
+        assert_eq!(client.state(), SrdState::AwaitingResult);
+
+        let mut output = Vec::new();
+        assert!(!server.authenticate(&delegate, &mut output).unwrap());
+        assert!(output.is_empty());
+        assert_eq!(server.state(), SrdState::AwaitingVerdict);
+        assert_eq!(
+            server.get_blob::<LogonBlob>().unwrap().unwrap(),
+            LogonBlob::new("fdubois", "Dummy123")
+        );
+
+        let mut result = Vec::new();
+        server.send_result(accepted, &mut result).unwrap();
+        assert!(server.send_result(accepted, &mut Vec::new()).is_err());
+
+        if accepted {
+            assert_eq!(server.state(), SrdState::Complete);
+            assert!(client.authenticate(&result, &mut output).unwrap());
+            assert_eq!(client.state(), SrdState::Complete);
+        } else {
+            assert_eq!(server.state(), SrdState::Failed);
+            match client.authenticate(&result, &mut output) {
+                Err(SrdError::InvalidCredentials) => {}
+                _ => assert!(false),
+            }
+            assert!(output.is_empty());
+            assert_eq!(client.state(), SrdState::Failed);
+        }
+    }
+
+    // The result is authenticated
+    let (mut client, mut server, delegate) = result_handshake(true, true);
+    server.authenticate(&delegate, &mut Vec::new()).unwrap();
+    let mut result = Vec::new();
+    server.send_result(false, &mut result).unwrap();
+    result[8] = 0;
+    match client.authenticate(&result, &mut Vec::new()) {
+        Err(SrdError::InvalidMac) => {}
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn result_message_not_negotiated() {
+    for &(client_result, server_result) in &[(true, false), (false, true)] {
+        let (client, mut server, delegate) = result_handshake(client_result, server_result);
+        assert_eq!(client.state(), SrdState::Complete);
+        assert!(server.authenticate(&delegate, &mut Vec::new()).unwrap());
+        assert!(server.send_result(true, &mut Vec::new()).is_err());
+    }
+}
+
+fn check_password(blob: &SrdBlob) -> bool {
+    let mut cursor = std::io::Cursor::new(blob.data());
+    match LogonBlob::read_from(&mut cursor) {
+        Ok(logon_blob) => logon_blob == LogonBlob::new("fdubois", "Dummy123"),
+        Err(_) => false,
+    }
+}
+
+fn validated_handshake(result_message: bool, password: &str) -> (Srd, Srd, ::Result<()>) {
+    let mut client = SrdBuilder::new(false).result_message(result_message).build().unwrap();
+    let mut server = SrdBuilder::new(true).result_message(result_message).build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", password)).unwrap();
+
+    server.set_credential_validator(Box::new(check_password)).unwrap();
+
+    let result = run_handshake(&mut client, &mut server);
+    (client, server, result)
+}
+
+#[test]
+fn credential_validator() {
+    for &result_message in &[true, false] {
+        let (client, server, result) = validated_handshake(result_message, "Dummy123");
+        result.unwrap();
+        assert_eq!(client.state(), SrdState::Complete);
+        assert_eq!(server.state(), SrdState::Complete);
+
+        let (client, server, result) = validated_handshake(result_message, "wrong");
+        match result {
+            Err(SrdError::InvalidCredentials) => {}
+            _ => assert!(false),
+        }
+        assert_eq!(server.state(), SrdState::Failed);
+        assert!(server.get_blob::<LogonBlob>().unwrap().is_some());
+        if result_message {
+            // Told by the result message, not by a generic error
+            assert_eq!(client.state(), SrdState::AwaitingResult);
+        }
+    }
+}
+
+#[test]
+fn credential_validator_result_reaches_client() {
+    for &(password, accepted) in &[("Dummy123", true), ("wrong", false)] {
+        let mut client = SrdBuilder::new(false).result_message(true).build().unwrap();
+        let mut server = SrdBuilder::new(true).result_message(true).build().unwrap();
+        client.set_blob(LogonBlob::new("fdubois", password)).unwrap();
+        server.set_credential_validator(Box::new(check_password)).unwrap();
+        assert!(client.set_credential_validator(Box::new(|_: &SrdBlob| true)).is_err());
+
+        let mut in_data = Vec::new();
+        let mut out_data = Vec::new();
+        for _ in 0..2 {
+            client.authenticate(&in_data, &mut out_data).unwrap();
+            in_data = std::mem::replace(&mut out_data, Vec::new());
+            server.authenticate(&in_data, &mut out_data).unwrap();
+            in_data = std::mem::replace(&mut out_data, Vec::new());
+        }
+        client.authenticate(&in_data, &mut out_data).unwrap();
+
+        // Delegate -> result, whatever the verdict
+        let mut result = Vec::new();
+        assert_eq!(server.authenticate(&out_data, &mut result).is_ok(), accepted);
+        assert!(!result.is_empty());
+
+        match client.authenticate(&result, &mut Vec::new()) {
+            Ok(true) => assert!(accepted),
+            Err(SrdError::InvalidCredentials) => assert!(!accepted),
+            _ => assert!(false),
+        }
+    }
+}
+
+#[test]
+fn challenge() {
+    let mut client = SrdBuilder::new(false).result_message(true).build().unwrap();
+    let mut server = SrdBuilder::new(true)
+        .result_message(true)
+        .challenge("Otp")
+        .challenge("Basic")
+        .build()
+        .unwrap();
+
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+    client.add_raw_blob(SrdBlob::new("Otp", b"123456"));
+    client.add_blob(BasicBlob::new("fdubois", "second")).unwrap();
+
+    let blob_types = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let validated = blob_types.clone();
+    server
+        .set_credential_validator(Box::new(move |blob: &SrdBlob| {
+            validated.lock().unwrap().push(blob.blob_type().to_owned());
+            true
+        }))
+        .unwrap();
+
+    run_handshake(&mut client, &mut server).unwrap();
+    assert_eq!(client.state(), SrdState::Complete);
+    assert_eq!(server.state(), SrdState::Complete);
+    assert_eq!(*blob_types.lock().unwrap(), vec!["Logon", "Otp", "Basic"]);
+
+    assert_eq!(
+        server.get_blob::<LogonBlob>().unwrap().unwrap(),
+        LogonBlob::new("fdubois", "Dummy123")
+    );
+    assert_eq!(
+        server.get_blob::<BasicBlob>().unwrap().unwrap(),
+        BasicBlob::new("fdubois", "second")
+    );
+}
+
+#[test]
+fn challenge_failures() {
+    // The client doesn't have the blob asked for, the server is told so
+    let mut client = SrdBuilder::new(false).result_message(true).build().unwrap();
+    let mut server = SrdBuilder::new(true).result_message(true).challenge("Otp").build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    let mut client_data = Vec::new();
+    let mut server_data = Vec::new();
+    client.authenticate(&[], &mut client_data).unwrap();
+    for _ in 0..2 {
+        server.authenticate(&client_data, &mut server_data).unwrap();
+        client_data.clear();
+        client.authenticate(&server_data, &mut client_data).unwrap();
+        server_data.clear();
+    }
+    assert!(!server.authenticate(&client_data, &mut server_data).unwrap());
+
+    client_data.clear();
+    match client.authenticate(&server_data, &mut client_data) {
+        Err(SrdError::MissingBlob) => {}
+        _ => assert!(false),
+    }
+    match server.authenticate(&client_data, &mut Vec::new()) {
+        Err(SrdError::PeerError(code, _)) if code == SrdError::MissingBlob.code() => {}
+        _ => assert!(false),
+    }
+
+    // A client that can't answer challenges is refused
+    let mut client = SrdBuilder::new(false).build().unwrap();
+    let mut server = SrdBuilder::new(true).result_message(true).challenge("Otp").build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+    client.add_raw_blob(SrdBlob::new("Otp", b"123456"));
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::Proto(_)) => {}
+        _ => assert!(false),
+    }
+
+    // Challenges are never asked without the result message
+    match SrdBuilder::new(true).challenge("Otp").build() {
+        Err(SrdError::Proto(_)) => {}
+        _ => assert!(false),
+    }
+    let builder = SrdBuilder::new(true).result_message(true);
+    assert!(builder.clone().skip_delegation(true).challenge("Otp").build().is_err());
+    assert!(builder.challenge("").build().is_err());
+}
+
+#[test]
+fn nonce_cache() {
+    let nonce_cache = Arc::new(MemoryNonceCache::new(3, Duration::from_secs(60)).unwrap());
+    let server_builder = SrdBuilder::new(true).nonce_cache(nonce_cache.clone());
+
+    for _ in 0..2 {
+        let mut client = SrdBuilder::new(false).build().unwrap();
+        let mut server = server_builder.clone().build().unwrap();
+        client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+        run_handshake(&mut client, &mut server).unwrap();
+    }
+    assert_eq!(nonce_cache.len(), 2);
+
+    // An accept message with a client nonce the server has already seen
+    let mut client = SrdBuilder::new(false).build().unwrap();
+    let mut server = server_builder.clone().build().unwrap();
+
+    let mut initiate = Vec::new();
+    client.authenticate(&[], &mut initiate).unwrap();
+    let mut offer = Vec::new();
+    server.authenticate(&initiate, &mut offer).unwrap();
+    let mut accept = Vec::new();
+    client.authenticate(&offer, &mut accept).unwrap();
+
+    match SrdMessage::read_from(&mut accept.as_slice()).unwrap() {
+        SrdMessage::Accept(_, accept) => nonce_cache.insert(&accept.nonce).unwrap(),
+        _ => panic!("Not an accept"),
+    }
+
+    match server.authenticate(&accept, &mut Vec::new()) {
+        Err(SrdError::ReplayDetected) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(server.state(), SrdState::Failed);
+
+    // The cache is full of nonces still in their window: the server fails rather than forget one
+    let mut client = SrdBuilder::new(false).build().unwrap();
+    let mut server = server_builder.build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+    match run_handshake(&mut client, &mut server) {
+        Err(SrdError::Internal(_)) => {}
+        _ => assert!(false),
+    }
+    assert_eq!(nonce_cache.len(), 3);
+}
+
+fn step_send(srd: &mut Srd, input: &[u8]) -> Vec<u8> {
+    match srd.step(input).unwrap() {
+        SrdOutcome::Send(output) => output,
+        _ => panic!("A message to send was expected"),
+    }
+}
+
+#[test]
+fn step() {
+    let mut client = SrdBuilder::new(false).build().unwrap();
+    let mut server = SrdBuilder::new(true).build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    let initiate = step_send(&mut client, &[]);
+    let offer = step_send(&mut server, &initiate);
+    let accept = step_send(&mut client, &offer);
+    let confirm = step_send(&mut server, &accept);
+
+    // The client is done once the delegate message is sent
+    let (delegate, client_info) = match client.step(&confirm).unwrap() {
+        SrdOutcome::SendAndComplete(delegate, info) => {
+            assert!(info.blob().is_none());
+            assert!(!info.use_cbt());
+            (delegate, info)
+        }
+        _ => panic!("The delegate message was expected"),
+    };
+
+    match server.step(&delegate).unwrap() {
+        SrdOutcome::Complete(info) => {
+            assert!(info.cipher() == client_info.cipher());
+            assert_eq!(info.key_size(), 256);
+            assert!(!info.use_cbt());
+            let blob = info.blob().unwrap();
+            assert_eq!(blob.blob_type(), "Logon");
+        }
+        _ => panic!("The server should be complete"),
+    }
+    assert_eq!(client.state(), SrdState::Complete);
+    assert_eq!(server.state(), SrdState::Complete);
+}
+
+#[test]
+fn step_verdict_and_failure() {
+    let mut client = SrdBuilder::new(false).result_message(true).build().unwrap();
+    let mut server = SrdBuilder::new(true).result_message(true).build().unwrap();
+    client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+
+    let initiate = step_send(&mut client, &[]);
+    let offer = step_send(&mut server, &initiate);
+    let accept = step_send(&mut client, &offer);
+    let confirm = step_send(&mut server, &accept);
+    let delegate = step_send(&mut client, &confirm);
+
+    match server.step(&delegate).unwrap() {
+        SrdOutcome::AwaitingVerdict => {}
+        _ => panic!("The server should wait for a verdict"),
+    }
+
+    let mut result = Vec::new();
+    server.send_result(true, &mut result).unwrap();
+    match client.step(&result).unwrap() {
+        SrdOutcome::Complete(info) => assert!(info.blob().is_none()),
+        _ => panic!("The client should be complete"),
+    }
+
+    // The error message for the peer is kept
+    let mut client = SrdBuilder::new(false).build().unwrap();
+    let mut server = SrdBuilder::new(true).build().unwrap();
+    let initiate = step_send(&mut client, &[]);
+    let offer = step_send(&mut server, &initiate);
+    let mut accept = step_send(&mut client, &offer);
+    let size = accept.len();
+    accept[size - 1] ^= 1;
+
+    match server.step(&accept) {
+        Err(SrdError::InvalidMac) => {}
+        _ => assert!(false),
+    }
+    let error = server.get_output_data().clone().unwrap();
+    match client.step(&error) {
+        Err(SrdError::PeerError(code, _)) if code == SrdError::InvalidMac.code() => {}
+        _ => assert!(false),
+    }
+}
@@ -0,0 +1,210 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Digest, Sha256};
+
+use srd_errors::SrdError;
+use Result;
+
+// Running hashes of the handshake messages, updated as they are sent and received. MACs cover every message without
+// their MAC field, and the key schedule and exported secrets use the messages as they were sent. Only the initiate
+// message is kept: the offer signature and the PAKE generator use it as is.
+//
+// The running hashes can't be serialized, only the hashes they give so far. A restored transcript can start a
+// handshake if it was serialized before the first message, and otherwise only gives the hashes of its handshake: a
+// context can be restored before its handshake or once it is complete, not in between.
+#[cfg_attr(feature = "ser", derive(Serialize, Deserialize))]
+#[derive(Clone)]
+pub(crate) struct Transcript {
+    initiate: Vec<u8>,
+    message_count: usize,
+    negotiation_hash: [u8; 32],
+    hash: [u8; 32],
+    #[cfg_attr(feature = "ser", serde(skip))]
+    running: Option<RunningHashes>,
+}
+
+#[derive(Clone)]
+struct RunningHashes {
+    hash: Sha256,
+    // MAC data received before the integrity key is known
+    mac_data: Vec<u8>,
+    hmac: Option<Hmac<Sha256>>,
+}
+
+impl Transcript {
+    pub fn new() -> Transcript {
+        Transcript {
+            initiate: Vec::new(),
+            message_count: 0,
+            negotiation_hash: [0u8; 32],
+            hash: Sha256::digest(&[]).into(),
+            running: Some(RunningHashes {
+                hash: Sha256::new(),
+                mac_data: Vec::new(),
+                hmac: None,
+            }),
+        }
+    }
+
+    pub fn add_message(&mut self, message: &[u8], has_mac: bool) -> Result<()> {
+        if has_mac && message.len() < 32 {
+            return Err(SrdError::InvalidDataLength);
+        }
+
+        let mac_data = if has_mac {
+            &message[..message.len() - 32]
+        } else {
+            message
+        };
+
+        let running = self.running_mut()?;
+        match running.hmac {
+            Some(ref mut hmac) => hmac.update(mac_data),
+            None => running.mac_data.extend_from_slice(mac_data),
+        }
+        running.hash.update(message);
+        let hash = running.hash.clone().finalize().into();
+
+        self.hash = hash;
+        self.message_count += 1;
+
+        match self.message_count {
+            1 => self.initiate = message.to_vec(),
+            2 => self.negotiation_hash = hash,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // The integrity key is only known once the keys are derived, after the offer
+    pub fn set_mac_key(&mut self, key: &[u8; 32]) -> Result<()> {
+        let running = self.running_mut()?;
+        if running.hmac.is_some() {
+            return Err(SrdError::Internal("MAC key already set".to_owned()));
+        }
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(key)?;
+        hmac.update(&running.mac_data);
+        running.mac_data = Vec::new();
+        running.hmac = Some(hmac);
+        Ok(())
+    }
+
+    // MAC of the transcript followed by a message about to be sent, without its MAC field
+    pub fn mac(&self, pending: &[u8]) -> Result<[u8; 32]> {
+        let mut hmac = self.hmac()?;
+        hmac.update(pending);
+        Ok(hmac.finalize().into_bytes().into())
+    }
+
+    pub fn verify_mac(&self, mac: &[u8]) -> Result<()> {
+        self.hmac()?.verify(mac).map_err(|_| SrdError::InvalidMac)
+    }
+
+    pub fn initiate(&self) -> &[u8] {
+        &self.initiate
+    }
+
+    // Hash of the initiate and offer messages
+    pub fn negotiation_hash(&self) -> &[u8; 32] {
+        &self.negotiation_hash
+    }
+
+    // Hash of every message so far
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    fn hmac(&self) -> Result<Hmac<Sha256>> {
+        match self.running {
+            Some(RunningHashes { hmac: Some(ref hmac), .. }) => Ok(hmac.clone()),
+            _ => Err(SrdError::Internal("MAC can't be calculated".to_owned())),
+        }
+    }
+
+    // Restored before the first message, the transcript starts afresh
+    fn running_mut(&mut self) -> Result<&mut RunningHashes> {
+        if self.running.is_none() && self.message_count == 0 {
+            *self = Transcript::new();
+        }
+
+        match self.running {
+            Some(ref mut running) => Ok(running),
+            None => Err(SrdError::BadSequence),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hmac::{Hmac, Mac, NewMac};
+    use sha2::{Digest, Sha256};
+    use transcript::Transcript;
+
+    #[test]
+    fn transcript_mac() {
+        let key = [0x42u8; 32];
+        let messages: Vec<Vec<u8>> = (1..6u8).map(|i| vec![i; 40 * i as usize]).collect();
+
+        let mut transcript = Transcript::new();
+        transcript.add_message(&messages[0], false).unwrap();
+        transcript.add_message(&messages[1], false).unwrap();
+        assert!(transcript.mac(&[]).is_err());
+
+        transcript.add_message(&messages[2], true).unwrap();
+        transcript.set_mac_key(&key).unwrap();
+        transcript.add_message(&messages[3], true).unwrap();
+
+        let mut mac_data = Vec::new();
+        mac_data.extend_from_slice(&messages[0]);
+        mac_data.extend_from_slice(&messages[1]);
+        mac_data.extend_from_slice(&messages[2][..messages[2].len() - 32]);
+        mac_data.extend_from_slice(&messages[3][..messages[3].len() - 32]);
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        hmac.update(&mac_data);
+        let mac = hmac.finalize().into_bytes();
+        transcript.verify_mac(&mac).unwrap();
+        assert!(transcript.verify_mac(&[0u8; 32]).is_err());
+
+        let mut hmac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        hmac.update(&mac_data);
+        hmac.update(&messages[4]);
+        assert_eq!(&transcript.mac(&messages[4]).unwrap()[..], &hmac.finalize().into_bytes()[..]);
+
+        assert_eq!(transcript.initiate(), &messages[0][..]);
+        let mut hash = Sha256::new();
+        hash.update(&messages[0]);
+        hash.update(&messages[1]);
+        assert_eq!(&transcript.negotiation_hash()[..], &hash.finalize()[..]);
+    }
+
+    #[cfg(feature = "ser")]
+    #[test]
+    fn transcript_restore() {
+        use bincode;
+
+        let messages: Vec<Vec<u8>> = (1..7u8).map(|i| vec![i; 100 * i as usize]).collect();
+
+        // Nothing to resume before the first message
+        let restored: Transcript = bincode::deserialize(&bincode::serialize(&Transcript::new()).unwrap()).unwrap();
+        let mut transcript = restored.clone();
+        transcript.add_message(&messages[0], false).unwrap();
+        transcript.add_message(&messages[1], false).unwrap();
+        let size = bincode::serialize(&transcript).unwrap().len();
+
+        transcript.set_mac_key(&[0x42u8; 32]).unwrap();
+        for message in &messages[2..] {
+            transcript.add_message(message, true).unwrap();
+        }
+        let serialized = bincode::serialize(&transcript).unwrap();
+        assert_eq!(serialized.len(), size);
+
+        // A restored transcript gives the hashes of the handshake, but can't go on with it
+        let mut restored: Transcript = bincode::deserialize(&serialized).unwrap();
+        assert_eq!(restored.hash(), transcript.hash());
+        assert_eq!(restored.negotiation_hash(), transcript.negotiation_hash());
+        assert_eq!(restored.initiate(), transcript.initiate());
+        assert!(restored.mac(&[]).is_err());
+        assert!(restored.add_message(&messages[0], false).is_err());
+    }
+}
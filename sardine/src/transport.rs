@@ -0,0 +1,146 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use srd::{SessionInfo, Srd, SrdOutcome};
+use srd_errors::SrdError;
+use Result;
+
+// SRD messages don't carry their size, so on a stream each one is sent as a frame: the size of the message as a
+// little-endian u32, then the message. Empty frames and frames over MAX_FRAME_SIZE are rejected before anything is
+// allocated.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+// Runs the whole handshake of a client context over a stream, until the last message is sent or received
+pub fn client_handshake<S: Read + Write>(stream: &mut S, srd: &mut Srd) -> Result<SessionInfo> {
+    if srd.config().is_server() {
+        return Err(SrdError::BadSequence);
+    }
+
+    run_handshake(stream, srd, Vec::new())
+}
+
+// Same as client_handshake(), for a server context. Delegated credentials have to be checked by a credential
+// validator when there is a result message: nothing else could accept them, so the context is refused before anything
+// is read.
+pub fn server_handshake<S: Read + Write>(stream: &mut S, srd: &mut Srd) -> Result<SessionInfo> {
+    if !srd.config().is_server() || srd.needs_verdict() {
+        return Err(SrdError::BadSequence);
+    }
+
+    let initiate = read_frame(stream)?;
+    run_handshake(stream, srd, initiate)
+}
+
+fn run_handshake<S: Read + Write>(stream: &mut S, srd: &mut Srd, mut input: Vec<u8>) -> Result<SessionInfo> {
+    loop {
+        match srd.step(&input) {
+            Ok(SrdOutcome::Send(output)) => {
+                write_frame(stream, &output)?;
+                input = read_frame(stream)?;
+            }
+            Ok(SrdOutcome::SendAndComplete(output, session_info)) => {
+                write_frame(stream, &output)?;
+                return Ok(session_info);
+            }
+            Ok(SrdOutcome::Complete(session_info)) => return Ok(session_info),
+            // Refused by server_handshake()
+            Ok(SrdOutcome::AwaitingVerdict) => return Err(SrdError::BadSequence),
+            Err(e) => {
+                // The peer is told why, if it is still listening
+                if let Some(ref error_msg) = *srd.get_output_data() {
+                    let _ = write_frame(stream, error_msg);
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let size = reader.read_u32::<LittleEndian>()? as usize;
+
+    if size == 0 || size > MAX_FRAME_SIZE {
+        return Err(SrdError::InvalidDataLength);
+    }
+
+    let mut frame = vec![0u8; size];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> Result<()> {
+    if frame.is_empty() || frame.len() > MAX_FRAME_SIZE {
+        return Err(SrdError::InvalidDataLength);
+    }
+
+    writer.write_u32::<LittleEndian>(frame.len() as u32)?;
+    writer.write_all(frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use blobs::LogonBlob;
+    use srd_config::SrdBuilder;
+    use srd_errors::SrdError;
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use transport::{client_handshake, read_frame, server_handshake, write_frame, MAX_FRAME_SIZE};
+
+    #[test]
+    fn handshake_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut server = SrdBuilder::new(true).build().unwrap();
+            let session_info = server_handshake(&mut stream, &mut server).unwrap();
+            assert_eq!(session_info.blob().unwrap().blob_type(), "Logon");
+            server.get_blob::<LogonBlob>().unwrap().unwrap()
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut client = SrdBuilder::new(false).build().unwrap();
+        client.set_blob(LogonBlob::new("fdubois", "Dummy123")).unwrap();
+        client_handshake(&mut stream, &mut client).unwrap();
+
+        assert_eq!(server.join().unwrap(), LogonBlob::new("fdubois", "Dummy123"));
+    }
+
+    #[test]
+    fn frame_size() {
+        let mut stream = Cursor::new(Vec::new());
+        write_frame(&mut stream, b"SRD").unwrap();
+        assert!(write_frame(&mut stream, &[]).is_err());
+        assert!(write_frame(&mut stream, &vec![0u8; MAX_FRAME_SIZE + 1]).is_err());
+        stream.set_position(0);
+        assert_eq!(read_frame(&mut stream).unwrap(), b"SRD");
+
+        for size in &[0u32, MAX_FRAME_SIZE as u32 + 1, u32::max_value()] {
+            let mut frame = Cursor::new(size.to_le_bytes().to_vec());
+            match read_frame(&mut frame) {
+                Err(SrdError::InvalidDataLength) => {}
+                _ => assert!(false),
+            }
+        }
+
+        // A server context can't run a client handshake
+        let mut server = SrdBuilder::new(true).build().unwrap();
+        match client_handshake(&mut Cursor::new(Vec::new()), &mut server) {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+
+        // Nor can a server that would have nothing to accept the credentials with, and nothing is read
+        let mut server = SrdBuilder::new(true).result_message(true).build().unwrap();
+        let mut stream = Cursor::new(vec![3, 0, 0, 0, 1, 2, 3]);
+        match server_handshake(&mut stream, &mut server) {
+            Err(SrdError::BadSequence) => {}
+            _ => assert!(false),
+        }
+        assert_eq!(stream.position(), 0);
+    }
+}